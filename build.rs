@@ -1,9 +1,11 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::configure()
-        //    .server_mod_attribute("routeguide", "#[tonic_mock::mock]")
-        .compile(
-            &["tests/protos/routeguide/route_guide.proto"],
-            &["tests/protos"],
-        )?;
+    tonic_mock_build::compile_protos(
+        tonic_mock_build::tonic_build::configure(),
+        &[
+            "tests/protos/routeguide/route_guide.proto",
+            "tests/protos/dummy/dummy.proto",
+        ],
+        &["tests/protos"],
+    )?;
     Ok(())
 }
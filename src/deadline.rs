@@ -0,0 +1,95 @@
+//! Deadlines set by the client, these are sent as the `grpc-timeout` header. The mock services
+//! only enforce them when asked to with `enforce_deadlines` on the builder, otherwise a slow
+//! responder takes as long as it takes.
+use crate::ResponseStream;
+use futures::future::{self, Either};
+use futures::{stream, Future, StreamExt};
+use std::time::Duration;
+use tokio::time::Instant;
+use tonic::metadata::MetadataMap;
+use tonic::{Response, Status};
+
+pub const GRPC_TIMEOUT_HEADER: &str = "grpc-timeout";
+
+/// The timeout the client set for the request, `None` if it didn't set one or it's invalid.
+pub fn grpc_timeout(metadata: &MetadataMap) -> Option<Duration> {
+    parse_grpc_timeout(metadata.get(GRPC_TIMEOUT_HEADER)?.to_str().ok()?)
+}
+
+/// Parse a `grpc-timeout` value, this is up to 8 digits followed by the unit, i.e. `100m` is
+/// 100 milliseconds.
+pub fn parse_grpc_timeout(value: &str) -> Option<Duration> {
+    if !value.is_ascii() || value.is_empty() {
+        return None;
+    }
+    let (amount, unit) = value.split_at(value.len() - 1);
+    if amount.is_empty() || amount.len() > 8 || !amount.bytes().all(|x| x.is_ascii_digit()) {
+        return None;
+    }
+    let amount = amount.parse::<u64>().ok()?;
+    match unit {
+        "H" => Some(Duration::from_secs(amount * 60 * 60)),
+        "M" => Some(Duration::from_secs(amount * 60)),
+        "S" => Some(Duration::from_secs(amount)),
+        "m" => Some(Duration::from_millis(amount)),
+        "u" => Some(Duration::from_micros(amount)),
+        "n" => Some(Duration::from_nanos(amount)),
+        _ => None,
+    }
+}
+
+/// The status a gRPC server fails the call with once the deadline has passed.
+pub fn deadline_exceeded() -> Status {
+    Status::deadline_exceeded("Deadline expired before operation could complete")
+}
+
+/// When a call has to be finished by, the generated mock services use this to enforce the
+/// client's deadline.
+#[derive(Clone, Copy, Debug)]
+pub struct Deadline(Option<Instant>);
+
+impl Deadline {
+    /// The deadline for a request received now, there's no deadline if it isn't enforced.
+    pub fn new(metadata: &MetadataMap, enforce: bool) -> Self {
+        let timeout = grpc_timeout(metadata).filter(|_| enforce);
+        Self(timeout.map(|x| Instant::now() + x))
+    }
+
+    /// Fail the response with `DEADLINE_EXCEEDED` if it isn't ready in time.
+    pub async fn response<U>(
+        self,
+        response: impl Future<Output = Result<Response<U>, Status>>,
+    ) -> Result<Response<U>, Status> {
+        match self.0 {
+            Some(deadline) => tokio::time::timeout_at(deadline, response)
+                .await
+                .unwrap_or_else(|_| Err(deadline_exceeded())),
+            None => response.await,
+        }
+    }
+
+    /// End a streamed response with `DEADLINE_EXCEEDED` if it's still going at the deadline.
+    pub fn stream<U: Send + 'static>(
+        self,
+        response: Result<Response<ResponseStream<U>>, Status>,
+    ) -> Result<Response<ResponseStream<U>>, Status> {
+        let Some(deadline) = self.0 else {
+            return response;
+        };
+        let (metadata, messages, extensions) = response?.into_parts();
+        let sleep = Box::pin(tokio::time::sleep_until(deadline));
+        let messages = stream::unfold(Some((messages, sleep)), |state| async move {
+            let (mut messages, mut sleep) = state?;
+            match future::select(messages.next(), &mut sleep).await {
+                Either::Left((Some(item), _)) => Some((item, Some((messages, sleep)))),
+                Either::Left((None, _)) => None,
+                Either::Right(_) => Some((Err(deadline_exceeded()), None)),
+            }
+        });
+        Ok(Response::from_parts(
+            metadata,
+            Box::pin(messages),
+            extensions,
+        ))
+    }
+}
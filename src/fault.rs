@@ -0,0 +1,342 @@
+//! Network faults the mock servers can inject instead of a gRPC response, so clients can be
+//! tested against connections which fail the way real ones do. Responders inject them with
+//! [`FaultResponse`](crate::responder::FaultResponse), the mock server does the rest:
+//!
+//! * the response is rewritten for faults injected before the response is sent
+//! * the response body is cut short for faults injected while a stream is being sent
+//! * the connection is shut down for GOAWAY and closing the connection
+use bytes::Bytes;
+use futures::task::AtomicWaker;
+use hyper::server::conn::AddrStream;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll, Waker};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tonic::body::BoxBody;
+use tonic::metadata::MetadataMap;
+use tonic::transport::server::{Connected, TcpConnectInfo};
+use tonic::Status;
+
+pub use h2::Reason;
+
+pub(crate) type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// A fault to inject in place of a normal response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// Reset the HTTP/2 stream with RST_STREAM and the error code
+    Reset(Reason),
+    /// Send GOAWAY with the error code. With `NO_ERROR` this is a graceful shutdown, calls already
+    /// in flight can finish but new ones have to go on a new connection. With any other code the
+    /// connection is closed straight away.
+    GoAway(Reason),
+    /// Close the TCP connection without saying goodbye
+    CloseConnection,
+    /// End the response without trailers, so the client never gets a gRPC status
+    Abort,
+}
+
+tokio::task_local! {
+    /// The faults for the request being handled, responders pick this up when they're called.
+    static REQUEST_FAULTS: Arc<RequestFaults>;
+}
+
+/// The faults for the request the current task is handling, `None` if the service isn't being
+/// run by a mock server.
+pub(crate) fn current() -> Option<Arc<RequestFaults>> {
+    REQUEST_FAULTS.try_with(Arc::clone).ok()
+}
+
+/// Inject a fault into the request the current task is handling. This returns the status for the
+/// call to fail with, though what the client sees is down to the fault.
+pub(crate) fn inject(fault: Fault) -> Status {
+    if let Some(faults) = current() {
+        faults.inject(fault);
+    }
+    injected(fault)
+}
+
+/// The status a call fails with when a fault is injected.
+pub(crate) fn injected(fault: Fault) -> Status {
+    Status::unavailable(format!("Injected fault: {:?}", fault))
+}
+
+/// Run the future handling a request with the request's faults available to the responders.
+pub(crate) async fn scope<F: std::future::Future>(faults: Arc<RequestFaults>, f: F) -> F::Output {
+    REQUEST_FAULTS.scope(faults, f).await
+}
+
+/// Faults which affect the whole connection.
+#[derive(Default)]
+pub(crate) struct ConnectionFaults {
+    go_away: Mutex<Option<Reason>>,
+    /// Woken to get hyper to check if the router is still ready, which is how GOAWAY is sent
+    ready_waker: AtomicWaker,
+    closed: AtomicBool,
+    /// Woken to fail the IO for the connection when it's closed
+    io_waker: AtomicWaker,
+    /// Reads from the connection. h2 sends what it has queued after reading all it can, so once
+    /// there's been a flush after a read everything queued before the read has been written.
+    reads: AtomicU64,
+    /// The reads before the last flush
+    flushed: AtomicU64,
+    /// Woken when the connection is flushed
+    flush_wakers: Mutex<Vec<Waker>>,
+}
+
+impl ConnectionFaults {
+    /// Whether the router can take more requests, hyper sends GOAWAY with the reason if not.
+    pub(crate) fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        self.ready_waker.register(cx.waker());
+        match *self.go_away.lock().unwrap() {
+            Some(reason) => Poll::Ready(Err(h2::Error::from(reason).into())),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    /// Mark the frames h2 has queued so far, for `poll_written`.
+    fn mark(&self) -> u64 {
+        self.reads.load(Ordering::SeqCst)
+    }
+
+    /// Ready once the frames queued before the mark have been written to the socket, or the
+    /// connection has gone.
+    fn poll_written(&self, mark: u64, cx: &mut Context<'_>) -> Poll<()> {
+        let mut wakers = self.flush_wakers.lock().unwrap();
+        if self.flushed.load(Ordering::SeqCst) > mark {
+            Poll::Ready(())
+        } else {
+            wakers.push(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+
+    fn flushed(&self, reads: u64) {
+        self.flushed.store(reads, Ordering::SeqCst);
+        for waker in self.flush_wakers.lock().unwrap().drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+/// Faults for a single request.
+pub(crate) struct RequestFaults {
+    connection: Arc<ConnectionFaults>,
+    fault: Mutex<Option<Fault>>,
+    /// Injected once the response body has been sent
+    at_end: Mutex<Option<Fault>>,
+}
+
+impl RequestFaults {
+    pub(crate) fn new(connection: Arc<ConnectionFaults>) -> Self {
+        Self {
+            connection,
+            fault: Mutex::new(None),
+            at_end: Mutex::new(None),
+        }
+    }
+
+    pub(crate) fn inject(&self, fault: Fault) {
+        *self.fault.lock().unwrap() = Some(fault);
+        match fault {
+            Fault::GoAway(reason) => {
+                *self.connection.go_away.lock().unwrap() = Some(reason);
+                self.connection.ready_waker.wake();
+            }
+            Fault::CloseConnection => {
+                self.connection.closed.store(true, Ordering::SeqCst);
+                self.connection.io_waker.wake();
+            }
+            Fault::Reset(_) | Fault::Abort => {}
+        }
+    }
+
+    /// Inject the fault after the rest of the response body has been written, so the client gets
+    /// the messages before the fault.
+    pub(crate) fn inject_at_end(&self, fault: Fault) {
+        *self.at_end.lock().unwrap() = Some(fault);
+    }
+
+    fn take_at_end(&self) -> Option<Fault> {
+        self.at_end.lock().unwrap().take()
+    }
+
+    pub(crate) fn get(&self) -> Option<Fault> {
+        *self.fault.lock().unwrap()
+    }
+}
+
+/// A response body which is cut short by a fault injected while it's being sent. As this wraps
+/// every response it also adds the trailers the responder set.
+pub(crate) struct FaultBody {
+    inner: BoxBody,
+    faults: Arc<RequestFaults>,
+    trailers: Option<MetadataMap>,
+    /// The fault to inject at the end of the body, once the data is written
+    at_end: Option<(Fault, u64)>,
+}
+
+impl FaultBody {
+    pub(crate) fn new(inner: BoxBody, faults: Arc<RequestFaults>) -> Self {
+        Self {
+            inner,
+            faults,
+            trailers: None,
+            at_end: None,
+        }
+    }
+
+    pub(crate) fn with_trailers(mut self, trailers: Option<MetadataMap>) -> Self {
+        self.trailers = trailers;
+        self
+    }
+
+    /// What to send instead of the rest of the body, `None` to carry on.
+    fn poll_fault<T>(&self) -> Option<Poll<Option<Result<T, BoxError>>>> {
+        match self.faults.get()? {
+            Fault::Reset(reason) => Some(Poll::Ready(Some(Err(h2::Error::from(reason).into())))),
+            Fault::Abort => Some(Poll::Ready(None)),
+            // Leave it to the connection being closed
+            Fault::CloseConnection => Some(Poll::Pending),
+            Fault::GoAway(_) => None,
+        }
+    }
+}
+
+impl http_body::Body for FaultBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        if let Some(fault) = self.poll_fault() {
+            return fault;
+        }
+        if self.at_end.is_none() {
+            let data = Pin::new(&mut self.inner).poll_data(cx);
+            // The fault can be injected while the next message is produced, in which case that's
+            // what ends the body
+            match self.poll_fault() {
+                Some(fault) if data.is_ready() => return fault,
+                _ => {}
+            }
+            let end = match data {
+                Poll::Ready(None) => self.faults.take_at_end(),
+                _ => None,
+            };
+            match end {
+                Some(fault) => self.at_end = Some((fault, self.faults.connection.mark())),
+                None => return data.map(|x| x.map(|x| x.map_err(Into::into))),
+            }
+        }
+        // Resetting the stream or closing the connection drops the frames h2 still has queued,
+        // so wait for the data to be written first
+        let (fault, mark) = self.at_end.unwrap();
+        ready!(self.faults.connection.poll_written(mark, cx));
+        self.faults.inject(fault);
+        self.poll_fault().unwrap_or(Poll::Ready(None))
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<http::HeaderMap>, Self::Error>> {
+        match self.faults.get() {
+            Some(Fault::Abort) => Poll::Ready(Ok(None)),
+            _ => {
+                let trailers = ready!(Pin::new(&mut self.inner).poll_trailers(cx))?;
+                let Some(extra) = self.trailers.take() else {
+                    return Poll::Ready(Ok(trailers));
+                };
+                // The status from tonic wins over anything the responder set
+                let mut merged = extra.into_headers();
+                merged.extend(trailers.unwrap_or_default());
+                Poll::Ready(Ok(Some(merged)))
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+}
+
+/// A connection to the mock server which can be closed by a fault, closing the connection makes
+/// every read and write fail so hyper drops the socket.
+pub(crate) struct FaultyConnection {
+    inner: AddrStream,
+    connect_info: TcpConnectInfo,
+    faults: Arc<ConnectionFaults>,
+}
+
+impl FaultyConnection {
+    pub(crate) fn new(inner: AddrStream) -> Self {
+        Self {
+            connect_info: inner.connect_info(),
+            inner,
+            faults: Default::default(),
+        }
+    }
+
+    pub(crate) fn connect_info(&self) -> TcpConnectInfo {
+        self.connect_info.clone()
+    }
+
+    pub(crate) fn faults(&self) -> Arc<ConnectionFaults> {
+        Arc::clone(&self.faults)
+    }
+
+    fn check_closed(&self, cx: &mut Context<'_>) -> io::Result<()> {
+        self.faults.io_waker.register(cx.waker());
+        if self.faults.closed.load(Ordering::SeqCst) {
+            Err(io::ErrorKind::ConnectionAborted.into())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl AsyncRead for FaultyConnection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.check_closed(cx)?;
+        self.faults.reads.fetch_add(1, Ordering::SeqCst);
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for FaultyConnection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.check_closed(cx)?;
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.check_closed(cx)?;
+        ready!(Pin::new(&mut self.inner).poll_flush(cx))?;
+        self.faults.flushed(self.faults.mark());
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl Drop for FaultyConnection {
+    fn drop(&mut self) {
+        // Nothing else will be written, so don't leave responses waiting for it
+        self.faults.flushed(u64::MAX);
+    }
+}
@@ -1,7 +1,12 @@
-use core::future::Future;
-use http::header::HeaderMap;
+// Every responder returns tonic's `Status` as the error, boxing it would only get in the way
+#![allow(clippy::result_large_err)]
+
+use crate::matchers::ReceivedStream;
+use futures::future::BoxFuture;
+use futures::Stream;
+use std::pin::Pin;
+use std::sync::Arc;
 use tokio::sync::broadcast::{self, error::RecvError};
-use tonic::codec::Codec;
 use tonic::metadata::MetadataMap;
 use tonic::{Request, Response, Status};
 use tracing::error;
@@ -9,41 +14,117 @@ use tracing::error;
 // Public macro reexport
 pub use tonic_mock_macros::mock;
 
+pub mod deadline;
+pub mod fault;
 pub mod matchers;
+pub mod recording;
 pub mod responder;
 pub mod server;
 pub mod times;
+pub mod verification;
+
+/// Exports used by the code generated from the `mock` attribute. Not public API.
+#[doc(hidden)]
+pub mod codegen {
+    pub use crate::deadline::Deadline;
+    pub use crate::matchers::{
+        BidirStreamMethodMock, ClientStreamMethodMock, MethodMocks, ServerStreamMethodMock,
+        UnaryMethodMock,
+    };
+    pub use crate::recording::{ReceivedCall, ReceivedRequest};
+    pub use crate::server::{serve, MockServer, ServerHandle};
+    pub use crate::verification::{DropVerifier, UnmockedCalls, VerificationReport};
+    pub use std::sync::Arc;
+    pub use tokio::sync::RwLock;
+}
 
 pub mod prelude {
+    pub use crate::deadline::grpc_timeout;
+    pub use crate::fault::{Fault, Reason};
     pub use crate::matchers::*;
+    pub use crate::recording::{ReceivedCall, ReceivedRequest};
     pub use crate::responder::*;
     pub use crate::server::*;
+    pub use crate::verification::VerificationReport;
+    pub use crate::{
+        AsyncResponder, BidirStreamResponder, IntoAsyncResponder, IntoStreamingResponder, Matcher,
+        RequestStream, Responder, ResponseStream, ServerStreamResponder, StreamingMatcher,
+        StreamingResponder,
+    };
 }
 
+/// The stream of messages sent back by the server for server streaming methods. This is the same
+/// type the generated mock services use for the streaming response types in the service trait.
+pub type ResponseStream<U> = Pin<Box<dyn Stream<Item = Result<U, Status>> + Send + 'static>>;
+
+/// The messages sent by the client in a bidirectional stream, this ends when the client
+/// half-closes the stream. If the stream fails, i.e. the client sends a message which can't be
+/// decoded, the error is the last item.
+pub type RequestStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send + 'static>>;
+
 pub trait Matcher<T> {
     fn matches(&self, request: &Request<T>) -> bool;
+
+    /// Describes the matcher in verification reports.
+    fn description(&self) -> String {
+        std::any::type_name::<Self>().to_string()
+    }
 }
 
 #[async_trait::async_trait]
 pub trait StreamingMatcher<T: Clone + Send + 'static> {
+    /// Describes the matcher in verification reports.
+    fn description(&self) -> String {
+        std::any::type_name::<Self>().to_string()
+    }
+
+    /// Whether the matcher looks at the messages or only the metadata. Combinators use this to
+    /// decide which check to negate or choose between.
+    fn checks_messages(&self) -> bool {
+        true
+    }
+
+    /// Whether the matcher looks at the trailing metadata. Bidirectional stream mocks check the
+    /// matchers which only look at the headers as soon as the call comes in, the rest wait for
+    /// the stream to end.
+    fn checks_trailers(&self) -> bool {
+        true
+    }
+
     // TODO this should probably be an Option
-    fn metadata_matches(&self, metadata: &MetadataMap, is_trailer: bool) -> bool {
+    fn metadata_matches(&self, _metadata: &MetadataMap, _is_trailer: bool) -> bool {
         true
     }
 
-    fn single_match(&self, value: &T) -> bool {
+    /// Check all the metadata sent with the request, this is what the method mocks use. The
+    /// trailers are `None` if the client didn't send any. By default the headers and trailers
+    /// are checked separately with `metadata_matches` and both have to pass.
+    fn request_metadata_matches(
+        &self,
+        header: &MetadataMap,
+        trailers: Option<&MetadataMap>,
+    ) -> bool {
+        self.metadata_matches(header, false)
+            && trailers
+                .map(|x| self.metadata_matches(x, true))
+                .unwrap_or(true)
+    }
+
+    fn single_match(&self, _value: &T) -> bool {
         true
     }
 
-    // TODO we need to think about sending errors down the channel
-    async fn stream_match(&self, mut rx: broadcast::Receiver<Option<T>>) -> bool {
+    /// Check the messages in the stream. The channel gets every message the client sent, then
+    /// `None` if the client half-closed the stream or the error if the stream failed. By default
+    /// every message has to pass `single_match`.
+    async fn stream_match(&self, mut rx: broadcast::Receiver<Option<Result<T, Status>>>) -> bool {
         let mut ret = true;
         let mut running = true;
         while running {
             let value = rx.recv().await;
             ret &= match value {
-                Ok(Some(s)) => self.single_match(&s),
-                Ok(None) | Err(RecvError::Closed) => {
+                Ok(Some(Ok(s))) => self.single_match(&s),
+                Ok(Some(Err(_))) | Ok(None) | Err(RecvError::Closed) => {
                     running = false;
                     true
                 }
@@ -56,27 +137,69 @@ pub trait StreamingMatcher<T: Clone + Send + 'static> {
         }
         ret
     }
+
+    /// Check the whole request, the metadata and the messages together. This is what the method
+    /// mocks use and what the combinators are built on, so metadata and message matchers can be
+    /// mixed freely. By default `request_metadata_matches` and `stream_match` both have to pass.
+    async fn request_matches(
+        &self,
+        header: &MetadataMap,
+        trailers: Option<&MetadataMap>,
+        stream: &ReceivedStream<T>,
+    ) -> bool
+    where
+        T: Sync,
+    {
+        self.request_metadata_matches(header, trailers) && self.stream_match(stream.replay()).await
+    }
 }
 
 pub trait Responder<T, U> {
-    fn respond(&self, request: Request<T>) -> Result<Response<U>, Status> {
+    fn respond(&self, _request: Request<T>) -> Result<Response<U>, Status> {
         Err(tonic::Status::unimplemented("Method is not implemented"))
     }
 }
 
+/// Responder for client streaming requests. It's given the request metadata, all the messages
+/// the client sent and the trailing metadata if the client sent any. If the stream failed before
+/// the client half-closed it, i.e. it sent a message which couldn't be decoded, `error` is the
+/// status it failed with.
 pub trait StreamingResponder<T, U> {
     fn response(
         &self,
-        header: &MetadataMap,
-        messages: broadcast::Receiver<Option<T>>,
+        _header: &MetadataMap,
+        _messages: &[T],
+        _trailers: Option<&MetadataMap>,
+        _error: Option<&Status>,
     ) -> Result<Response<U>, Status> {
         Err(tonic::Status::unimplemented("Method is not implemented"))
     }
 }
 
+/// Responder for server streaming requests. Returning an `Err` in the stream ends it with that
+/// status.
+pub trait ServerStreamResponder<T, U> {
+    fn respond(&self, _request: Request<T>) -> Result<Response<ResponseStream<U>>, Status> {
+        Err(tonic::Status::unimplemented("Method is not implemented"))
+    }
+}
+
+/// Responder for bidirectional streaming requests. It's given the client messages as they arrive
+/// so it can respond to them as the conversation goes on. It doesn't have to read them all, the
+/// mock reads the stream to the end either way so the request is still matched and recorded.
+pub trait BidirStreamResponder<T, U> {
+    fn respond(
+        &self,
+        _header: &MetadataMap,
+        _messages: RequestStream<T>,
+    ) -> Result<Response<ResponseStream<U>>, Status> {
+        Err(tonic::Status::unimplemented("Method is not implemented"))
+    }
+}
+
 #[async_trait::async_trait]
 pub trait AsyncResponder<T, U> {
-    async fn respond(&self, request: Request<T>) -> Result<Response<U>, Status>
+    async fn respond(&self, _request: Request<T>) -> Result<Response<U>, Status>
     where
         T: Send + 'static,
     {
@@ -94,3 +217,133 @@ where
         Responder::respond(self, request)
     }
 }
+
+/// Responder for client streaming requests which has to wait on something before responding,
+/// i.e. a [`Delayed`](responder::Delayed) one. This is implemented for every
+/// `StreamingResponder`, so it isn't in the prelude where its `response` would clash with theirs.
+#[async_trait::async_trait]
+pub trait AsyncStreamingResponder<T, U> {
+    async fn response(
+        &self,
+        _header: &MetadataMap,
+        _messages: &[T],
+        _trailers: Option<&MetadataMap>,
+        _error: Option<&Status>,
+    ) -> Result<Response<U>, Status>
+    where
+        T: Sync,
+    {
+        Err(tonic::Status::unimplemented("Method is not implemented"))
+    }
+}
+
+#[async_trait::async_trait]
+impl<R, T, U> AsyncStreamingResponder<T, U> for R
+where
+    R: StreamingResponder<T, U> + Sync,
+{
+    async fn response(
+        &self,
+        header: &MetadataMap,
+        messages: &[T],
+        trailers: Option<&MetadataMap>,
+        error: Option<&Status>,
+    ) -> Result<Response<U>, Status>
+    where
+        T: Sync,
+    {
+        StreamingResponder::response(self, header, messages, trailers, error)
+    }
+}
+
+/// A unary responder with its type erased, this is what the unary mocks hold. Not public API.
+#[doc(hidden)]
+pub type BoxResponder<T, U> =
+    Arc<dyn Fn(Request<T>) -> BoxFuture<'static, Result<Response<U>, Status>> + Send + Sync>;
+
+/// Anything a unary mock can respond with: a [`Responder`], an [`AsyncResponder`], an
+/// [`AsyncResponseFn`](responder::AsyncResponseFn) or a [`Delayed`](responder::Delayed) or
+/// [`Chaos`](responder::Chaos) wrapping any of them. The marker `M` only keeps the
+/// implementations apart, it's inferred so it never has to be written out.
+pub trait IntoAsyncResponder<T, U, M> {
+    #[doc(hidden)]
+    fn into_boxed(self) -> BoxResponder<T, U>;
+}
+
+/// A client streaming responder with its type erased, this is what the client streaming mocks
+/// hold. Not public API.
+#[doc(hidden)]
+pub type BoxStreamingResponder<T, U> = Arc<
+    dyn for<'a> Fn(
+            &'a MetadataMap,
+            &'a [T],
+            Option<&'a MetadataMap>,
+            Option<&'a Status>,
+        ) -> BoxFuture<'a, Result<Response<U>, Status>>
+        + Send
+        + Sync,
+>;
+
+/// Anything a client streaming mock can respond with: a [`StreamingResponder`], an
+/// [`AsyncStreamingResponder`] or a [`Delayed`](responder::Delayed) wrapping either. Like
+/// [`IntoAsyncResponder`] the marker `M` is inferred.
+pub trait IntoStreamingResponder<T, U, M> {
+    #[doc(hidden)]
+    fn into_boxed(self) -> BoxStreamingResponder<T, U>;
+}
+
+/// Give the closure the higher-ranked signature a `BoxStreamingResponder` needs, the compiler
+/// only infers it when the closure is passed straight to a function with the bound.
+pub(crate) fn box_streaming<T, U, F>(f: F) -> BoxStreamingResponder<T, U>
+where
+    F: for<'a> Fn(
+            &'a MetadataMap,
+            &'a [T],
+            Option<&'a MetadataMap>,
+            Option<&'a Status>,
+        ) -> BoxFuture<'a, Result<Response<U>, Status>>
+        + Send
+        + Sync
+        + 'static,
+{
+    Arc::new(f)
+}
+
+/// Markers for the kinds of [`IntoAsyncResponder`] and [`IntoStreamingResponder`], wrappers carry the marker of the responder
+/// they wrap.
+mod marker {
+    use std::marker::PhantomData;
+
+    pub struct Async;
+    pub struct Closure;
+    pub struct Delayed<M>(PhantomData<M>);
+    pub struct Chaos<M>(PhantomData<M>);
+}
+
+impl<R, T, U> IntoAsyncResponder<T, U, marker::Async> for R
+where
+    T: Send + 'static,
+    R: AsyncResponder<T, U> + Send + Sync + 'static,
+{
+    fn into_boxed(self) -> BoxResponder<T, U> {
+        let responder = Arc::new(self);
+        Arc::new(move |request| {
+            let responder = Arc::clone(&responder);
+            Box::pin(async move { responder.respond(request).await })
+        })
+    }
+}
+
+impl<R, T, U> IntoStreamingResponder<T, U, marker::Async> for R
+where
+    T: Sync,
+    R: AsyncStreamingResponder<T, U> + Send + Sync + 'static,
+{
+    fn into_boxed(self) -> BoxStreamingResponder<T, U> {
+        let responder = Arc::new(self);
+        box_streaming(move |header, messages, trailers, error| {
+            let responder = Arc::clone(&responder);
+            Box::pin(async move { responder.response(header, messages, trailers, error).await })
+        })
+    }
+}
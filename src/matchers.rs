@@ -1,12 +1,23 @@
+use crate::deadline::grpc_timeout;
+use crate::recording::{ReceivedRequest, RequestLog};
 use crate::responder::*;
+use crate::server::RequestCancelled;
 use crate::times::*;
+use crate::verification::{CallRecorder, MethodReport};
 use crate::*;
-use futures::stream::{FuturesOrdered, StreamExt};
-use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-use tokio::sync::broadcast;
-use tonic::metadata::MetadataKey;
-use tonic::{Request, Response, Status, Streaming};
-use tracing::trace;
+use bytes::Buf;
+use prost::encoding::{decode_key, decode_varint, WireType};
+use prost::DecodeError;
+use regex::Regex;
+use std::fmt::Debug;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tonic::{Code, Request, Response, Status, Streaming};
+use tracing::error;
 
 pub enum RequestType {
     Unary,
@@ -15,38 +26,228 @@ pub enum RequestType {
     BidirStream,
 }
 
-pub struct UnaryMethodMock<T, U> {
-    matchers: Vec<Match<T>>,
-    response: Box<dyn Responder<T, U> + Send + Sync>,
-    called: AtomicU64,
-    expected_calls: Option<Times>,
-    all_matched: AtomicBool,
+/// What differs between the kinds of method mock: the request message, how requests are matched
+/// and what responds to them.
+pub trait MockKind {
+    type Request;
+    type Matcher;
+    type Responder;
+
+    /// The responder a mock starts with, it fails every call with `UNIMPLEMENTED`.
+    fn unimplemented() -> Self::Responder;
+}
+
+/// The kinds of method mock, one for each kind of gRPC method.
+pub mod kind {
+    use super::*;
+    use std::marker::PhantomData;
+
+    pub struct Unary<T, U>(PhantomData<fn(T) -> U>);
+
+    pub struct ServerStream<T, U>(PhantomData<fn(T) -> U>);
+
+    pub struct ClientStream<T, U>(PhantomData<fn(T) -> U>);
+
+    pub struct BidirStream<T, U>(PhantomData<fn(T) -> U>);
+
+    impl<T: Send + 'static, U> MockKind for Unary<T, U> {
+        type Request = T;
+        type Matcher = Match<T>;
+        type Responder = BoxResponder<T, U>;
+
+        fn unimplemented() -> Self::Responder {
+            IntoAsyncResponder::into_boxed(Unimplemented)
+        }
+    }
+
+    impl<T, U> MockKind for ServerStream<T, U> {
+        type Request = T;
+        type Matcher = Match<T>;
+        type Responder = Box<dyn ServerStreamResponder<T, U> + Send + Sync>;
+
+        fn unimplemented() -> Self::Responder {
+            Box::new(Unimplemented)
+        }
+    }
+
+    impl<T: Clone + Send + Sync + 'static, U> MockKind for ClientStream<T, U> {
+        type Request = T;
+        type Matcher = Arc<StreamingMatch<T>>;
+        type Responder = BoxStreamingResponder<T, U>;
+
+        fn unimplemented() -> Self::Responder {
+            IntoStreamingResponder::into_boxed(Unimplemented)
+        }
+    }
+
+    impl<T: Clone + Send + 'static, U> MockKind for BidirStream<T, U> {
+        type Request = T;
+        type Matcher = Arc<StreamingMatch<T>>;
+        type Responder = Box<dyn BidirStreamResponder<T, U> + Send + Sync>;
+
+        fn unimplemented() -> Self::Responder {
+            Box::new(Unimplemented)
+        }
+    }
+}
+
+/// A mock for a gRPC method. Requests are checked against its matchers and answered by its
+/// responder, and the calls are recorded to verify against what was expected.
+pub struct MethodMock<K: MockKind> {
+    matchers: Vec<K::Matcher>,
+    response: K::Responder,
+    calls: CallRecorder,
+    received: RequestLog<K::Request>,
+    priority: u32,
 }
 
-impl<T, U> Default for UnaryMethodMock<T, U> {
+pub type UnaryMethodMock<T, U> = MethodMock<kind::Unary<T, U>>;
+pub type ServerStreamMethodMock<T, U> = MethodMock<kind::ServerStream<T, U>>;
+pub type ClientStreamMethodMock<T, U> = MethodMock<kind::ClientStream<T, U>>;
+pub type BidirStreamMethodMock<T, U> = MethodMock<kind::BidirStream<T, U>>;
+
+impl<K: MockKind> Default for MethodMock<K> {
     fn default() -> Self {
         Self {
             matchers: vec![],
-            response: Box::new(Unimplemented),
-            expected_calls: None,
-            called: AtomicU64::new(0),
-            all_matched: AtomicBool::new(true),
+            response: K::unimplemented(),
+            calls: CallRecorder::default(),
+            received: RequestLog::default(),
+            priority: 0,
+        }
+    }
+}
+
+impl<K: MockKind> MethodMock<K> {
+    /// Name the mock, this is used to identify it in the verification report.
+    pub fn name(&mut self, name: impl Into<String>) -> &mut Self {
+        self.calls.set_name(name.into());
+        self
+    }
+
+    /// When there are several mocks for a method, the ones with a higher priority are tried
+    /// first. The default is 0.
+    pub fn priority(&mut self, priority: u32) -> &mut Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn expect(&mut self, calls: impl Into<Times>) -> &mut Self {
+        self.calls.expect(calls.into());
+        self
+    }
+
+    pub fn reset(&self) {
+        self.calls.reset();
+        self.received.clear();
+    }
+
+    pub fn verify(&self) -> MethodReport {
+        self.calls.report()
+    }
+
+    /// The requests this mock has handled, in the order they were received.
+    pub fn received_requests(&self) -> Vec<ReceivedRequest<K::Request>>
+    where
+        K::Request: Clone,
+    {
+        self.received.get()
+    }
+}
+
+impl<T: Send + 'static, U> UnaryMethodMock<T, U> {
+    pub async fn process_request(&self, request: Request<T>) -> Result<Response<U>, Status>
+    where
+        T: Clone + Debug,
+    {
+        self.record_mismatches(&request);
+        self.respond(request).await
+    }
+
+    fn all_match(&self, request: &Request<T>) -> bool {
+        self.matchers.iter().all(|x| x.matches(request))
+    }
+
+    fn record_mismatches(&self, request: &Request<T>)
+    where
+        T: Debug,
+    {
+        for matcher in &self.matchers {
+            if !matcher.matches(request) {
+                self.calls
+                    .mismatch(matcher.description(), format!("{:?}", request));
+            }
         }
     }
+
+    async fn respond(&self, request: Request<T>) -> Result<Response<U>, Status>
+    where
+        T: Clone,
+    {
+        self.calls.called();
+        self.received.record(ReceivedRequest::unary(&request));
+        (self.response)(request).await
+    }
+
+    pub fn add_matcher(&mut self, m: impl Matcher<T> + Send + Sync + 'static) -> &mut Self {
+        self.matchers.push(Match {
+            matcher: Box::new(m),
+        });
+        self
+    }
+
+    /// Respond with a `Responder`, an `AsyncResponder` or a wrapper such as [`Delayed`] around
+    /// one.
+    pub fn response<M>(&mut self, r: impl IntoAsyncResponder<T, U, M>) -> &mut Self {
+        self.response = r.into_boxed();
+        self
+    }
+
+    /// Respond with the result of an async closure, this is short for
+    /// `response(AsyncResponseFn::new(f))`.
+    pub fn async_response<F, Fut>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(Request<T>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Response<U>, Status>> + Send + 'static,
+    {
+        self.response(AsyncResponseFn::new(f))
+    }
 }
 
-impl<T, U> UnaryMethodMock<T, U> {
-    pub fn process_request(&self, request: Request<T>) -> Result<Response<U>, Status> {
-        self.called.fetch_add(1, Ordering::SeqCst);
-        let mut matches = true;
+impl<T, U> ServerStreamMethodMock<T, U> {
+    pub fn process_request(
+        &self,
+        request: Request<T>,
+    ) -> Result<Response<ResponseStream<U>>, Status>
+    where
+        T: Clone + Debug,
+    {
+        self.record_mismatches(&request);
+        self.respond(request)
+    }
+
+    fn all_match(&self, request: &Request<T>) -> bool {
+        self.matchers.iter().all(|x| x.matches(request))
+    }
+
+    fn record_mismatches(&self, request: &Request<T>)
+    where
+        T: Debug,
+    {
         for matcher in &self.matchers {
-            matches &= matcher.matches(&request);
-            if !matches {
-                self.all_matched.fetch_and(false, Ordering::SeqCst);
-                // If we've failed matching do we want to send the response back?
-                break;
+            if !matcher.matches(request) {
+                self.calls
+                    .mismatch(matcher.description(), format!("{:?}", request));
             }
         }
+    }
+
+    fn respond(&self, request: Request<T>) -> Result<Response<ResponseStream<U>>, Status>
+    where
+        T: Clone,
+    {
+        self.calls.called();
+        self.received.record(ReceivedRequest::unary(&request));
         self.response.respond(request)
     }
 
@@ -57,98 +258,507 @@ impl<T, U> UnaryMethodMock<T, U> {
         self
     }
 
-    pub fn response(&mut self, r: impl Responder<T, U> + Send + Sync + 'static) -> &mut Self {
+    pub fn response(
+        &mut self,
+        r: impl ServerStreamResponder<T, U> + Send + Sync + 'static,
+    ) -> &mut Self {
         self.response = Box::new(r);
         self
     }
+}
 
-    pub fn expect(&mut self, calls: impl Into<Times>) -> &mut Self {
-        self.expected_calls = Some(calls.into());
+/// Several mocks for the same method, each request is handled by the first mock whose matchers
+/// all pass. Mocks with a higher priority are tried first, otherwise they're tried in the order
+/// they were added. If no mock matches, the request fails with the `no_match` status and the
+/// failing matchers are recorded as mismatches.
+///
+/// Bidirectional streams are routed on the matchers which only look at the headers, as the
+/// responder starts before the messages arrive.
+pub struct MethodMocks<M> {
+    name: String,
+    mocks: Vec<M>,
+    no_match: Status,
+    /// Records the requests which no mock matched
+    unmatched: M,
+}
+
+impl<K: MockKind> MethodMocks<MethodMock<K>> {
+    pub fn new(name: impl Into<String>) -> Self {
+        let name = name.into();
+        Self {
+            no_match: Status::not_found(format!("No mock matched the {} request", name)),
+            name,
+            mocks: vec![],
+            unmatched: MethodMock::default(),
+        }
+    }
+
+    /// The status returned when no mock matches a request, this is `NOT_FOUND` by default.
+    pub fn no_match(&mut self, status: Status) -> &mut Self {
+        self.no_match = status;
         self
     }
 
-    pub fn reset(&mut self) {
-        self.called.store(0, Ordering::SeqCst);
-        self.all_matched.store(true, Ordering::SeqCst);
+    /// Add a default mock, it's named after the method or the method and its position if there
+    /// are already mocks for the method.
+    pub fn add_mock(&mut self) -> &mut MethodMock<K> {
+        let name = match self.mocks.len() {
+            0 => self.name.clone(),
+            n => format!("{} #{}", self.name, n + 1),
+        };
+        self.mocks.push(MethodMock::default());
+        self.mocks.last_mut().unwrap().name(name)
     }
 
-    pub fn verify(&self) -> bool {
-        if let Some(calls) = self.expected_calls.as_ref() {
-            let actual = self.called.load(Ordering::Relaxed);
-            if !calls.contains(actual) {
-                return false;
+    /// The mocks in the order they're tried.
+    fn by_priority(&self) -> Vec<&MethodMock<K>> {
+        let mut mocks = self.mocks.iter().collect::<Vec<_>>();
+        // Stable sort so mocks with the same priority are tried in the order they were added
+        mocks.sort_by_key(|x| std::cmp::Reverse(x.priority));
+        mocks
+    }
+
+    pub fn reset(&self) {
+        self.mocks.iter().for_each(|x| x.reset());
+        self.unmatched.reset();
+    }
+
+    pub fn verify(&self) -> Vec<MethodReport> {
+        self.mocks.iter().map(|x| x.verify()).collect()
+    }
+
+    /// All the requests for the method in the order they were received, including the ones no
+    /// mock matched.
+    pub fn received_requests(&self) -> Vec<ReceivedRequest<K::Request>>
+    where
+        K::Request: Clone,
+    {
+        let mut requests = self
+            .mocks
+            .iter()
+            .chain(Some(&self.unmatched))
+            .flat_map(|x| x.received_requests())
+            .collect::<Vec<_>>();
+        requests.sort_by_key(|x| x.received_at);
+        requests
+    }
+}
+
+impl<T: Send + 'static, U> MethodMocks<UnaryMethodMock<T, U>> {
+    pub async fn process_request(&self, request: Request<T>) -> Result<Response<U>, Status>
+    where
+        T: Clone + Debug,
+    {
+        let mocks = self.by_priority();
+        match mocks.iter().find(|x| x.all_match(&request)) {
+            Some(mock) => mock.respond(request).await,
+            None => {
+                for mock in mocks {
+                    mock.record_mismatches(&request);
+                }
+                self.unmatched
+                    .received
+                    .record(ReceivedRequest::unary(&request));
+                Err(self.no_match.clone())
+            }
+        }
+    }
+}
+
+impl<T, U> MethodMocks<ServerStreamMethodMock<T, U>> {
+    pub fn process_request(
+        &self,
+        request: Request<T>,
+    ) -> Result<Response<ResponseStream<U>>, Status>
+    where
+        T: Clone + Debug,
+    {
+        let mocks = self.by_priority();
+        match mocks.iter().find(|x| x.all_match(&request)) {
+            Some(mock) => mock.respond(request),
+            None => {
+                for mock in mocks {
+                    mock.record_mismatches(&request);
+                }
+                self.unmatched
+                    .received
+                    .record(ReceivedRequest::unary(&request));
+                Err(self.no_match.clone())
             }
         }
-        self.all_matched.load(Ordering::Relaxed)
     }
 }
 
-pub struct ClientStreamMethodMock<T: Clone + Send + 'static, U> {
-    matchers: Vec<StreamingMatch<T>>,
-    response: Box<dyn Responder<T, U> + Send + Sync>,
+impl<T, U> MethodMocks<ClientStreamMethodMock<T, U>>
+where
+    T: Clone + Send + Sync + 'static,
+{
+    /// The whole stream is read before the mocks are tried, as their matchers can check any of
+    /// the messages.
+    pub async fn process_request(
+        &self,
+        request: Request<Streaming<T>>,
+    ) -> Result<Response<U>, Status>
+    where
+        T: Debug,
+        U: Send + 'static,
+    {
+        let mocks = self.by_priority().into_iter().cloned().collect::<Vec<_>>();
+        let unmatched = self.unmatched.received.clone();
+        let no_match = self.no_match.clone();
+        handle_stream(async move {
+            let request = StreamRequest::receive(request).await;
+            for mock in &mocks {
+                if mock.all_match(&request).await {
+                    return mock.respond(request).await;
+                }
+            }
+            for mock in &mocks {
+                mock.record_mismatches(&request).await;
+            }
+            unmatched.record(request.into_received());
+            Err(no_match)
+        })
+        .await
+    }
 }
 
-impl<T, U> ClientStreamMethodMock<T, U>
+impl<T, U> MethodMocks<BidirStreamMethodMock<T, U>>
 where
     T: Clone + Send + 'static,
+{
+    /// The responder starts before the messages arrive, so requests are routed on the matchers
+    /// which only look at the headers. The other matchers are checked by the chosen mock once
+    /// the stream ends.
+    pub fn process_request(
+        &self,
+        request: Request<Streaming<T>>,
+    ) -> Result<Response<ResponseStream<U>>, Status>
+    where
+        T: Debug + Sync,
+    {
+        let mocks = self.by_priority();
+        match mocks.iter().find(|x| x.header_matches(request.metadata())) {
+            Some(mock) => mock.respond(request),
+            None => {
+                for mock in mocks {
+                    mock.record_header_mismatches(request.metadata());
+                }
+                // Nothing reads the messages but the request is still recorded once it ends
+                let _ = read_bidir_stream(
+                    request,
+                    vec![],
+                    self.unmatched.calls.clone(),
+                    self.unmatched.received.clone(),
+                );
+                Err(self.no_match.clone())
+            }
+        }
+    }
+}
+
+/// Read, match and respond to a client stream on its own task. Hyper drops the handler when the
+/// client cancels the call, this way a cancelled stream still reaches the matchers, the responder
+/// and the request log.
+async fn handle_stream<U: Send + 'static>(
+    handler: impl Future<Output = Result<Response<U>, Status>> + Send + 'static,
+) -> Result<Response<U>, Status> {
+    // The request's faults are carried over so the responder can still inject them
+    let handle = match crate::fault::current() {
+        Some(faults) => tokio::spawn(crate::fault::scope(faults, handler)),
+        None => tokio::spawn(handler),
+    };
+    match handle.await {
+        Ok(response) => response,
+        Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+        Err(_) => Err(Status::cancelled("The mock server is shutting down")),
+    }
+}
+
+/// A client stream which has been read to the end, so it can be matched, responded to and
+/// recorded.
+struct StreamRequest<T> {
+    metadata: MetadataMap,
+    stream: ReceivedStream<T>,
+    trailers: Option<MetadataMap>,
+    remote_addr: Option<SocketAddr>,
+    received_at: SystemTime,
+}
+
+impl<T> StreamRequest<T> {
+    async fn receive(request: Request<Streaming<T>>) -> Self {
+        Self::receive_with(request, |_| {}).await
+    }
+
+    /// Read the stream to the end, passing each message to `on_message` as it arrives.
+    async fn receive_with(request: Request<Streaming<T>>, mut on_message: impl FnMut(&T)) -> Self {
+        let remote_addr = request.remote_addr();
+        let received_at = SystemTime::now();
+        let (metadata, extensions, mut stream) = request.into_parts();
+        let cancelled = extensions.get::<RequestCancelled>().cloned();
+
+        let mut messages = vec![];
+        let error = loop {
+            match stream.message().await {
+                Ok(Some(message)) => {
+                    on_message(&message);
+                    messages.push(message);
+                }
+                Ok(None) => break None,
+                Err(status) => break Some(status),
+            }
+        };
+        let trailers = stream.trailers().await.ok().flatten();
+        // The stream ends cleanly when the client cancels, reading the trailers tells them apart
+        let error = error.or_else(|| cancelled.and_then(|x| x.status()));
+        Self {
+            metadata,
+            stream: ReceivedStream { messages, error },
+            trailers,
+            remote_addr,
+            received_at,
+        }
+    }
+
+    fn describe(&self) -> String
+    where
+        T: Debug,
+    {
+        format!(
+            "metadata: {:?}, messages: {:?}, trailers: {:?}, error: {:?}",
+            self.metadata, self.stream.messages, self.trailers, self.stream.error
+        )
+    }
+
+    fn into_received(self) -> ReceivedRequest<T> {
+        ReceivedRequest {
+            metadata: self.metadata,
+            messages: self.stream.messages,
+            trailers: self.trailers,
+            error: self.stream.error,
+            remote_addr: self.remote_addr,
+            received_at: self.received_at,
+        }
+    }
+}
+
+/// Clones share the calls, request log and responder, so a clone can handle a request on its own
+/// task.
+impl<T: Clone + Send + Sync + 'static, U> Clone for ClientStreamMethodMock<T, U> {
+    fn clone(&self) -> Self {
+        Self {
+            matchers: self.matchers.clone(),
+            response: Arc::clone(&self.response),
+            calls: self.calls.clone(),
+            received: self.received.clone(),
+            priority: self.priority,
+        }
+    }
+}
+
+impl<T, U> ClientStreamMethodMock<T, U>
+where
+    T: Clone + Send + Sync + 'static,
 {
     pub async fn process_request(
         &self,
         request: Request<Streaming<T>>,
-    ) -> Result<Response<U>, Status> {
-        let mut matches = true;
-        let metadata = request.metadata();
-        let mut metadata_matches = vec![];
+    ) -> Result<Response<U>, Status>
+    where
+        T: Debug,
+        U: Send + 'static,
+    {
+        let mock = self.clone();
+        handle_stream(async move {
+            let request = StreamRequest::receive(request).await;
+            mock.record_mismatches(&request).await;
+            mock.respond(request).await
+        })
+        .await
+    }
 
+    async fn all_match(&self, request: &StreamRequest<T>) -> bool {
         for matcher in &self.matchers {
-            metadata_matches.push(matcher.metadata_matches(metadata, false));
+            if !matcher
+                .request_matches(
+                    &request.metadata,
+                    request.trailers.as_ref(),
+                    &request.stream,
+                )
+                .await
+            {
+                return false;
+            }
         }
-        let mut matcher_results = FuturesOrdered::new();
-        let (tx, rx) = broadcast::channel(32);
+        true
+    }
 
+    async fn record_mismatches(&self, request: &StreamRequest<T>)
+    where
+        T: Debug,
+    {
+        // Each matcher gets the whole stream replayed to it, so no messages are lost however
+        // long the stream is or however slowly the matchers read it.
         for matcher in &self.matchers {
-            matcher_results.push(matcher.stream_match(tx.subscribe()));
+            if !matcher
+                .request_matches(
+                    &request.metadata,
+                    request.trailers.as_ref(),
+                    &request.stream,
+                )
+                .await
+            {
+                self.calls
+                    .mismatch(matcher.description(), request.describe());
+            }
         }
-        std::mem::drop(rx);
+    }
+
+    async fn respond(&self, request: StreamRequest<T>) -> Result<Response<U>, Status> {
+        self.calls.called();
+        let response = (self.response)(
+            &request.metadata,
+            &request.stream.messages,
+            request.trailers.as_ref(),
+            request.stream.error.as_ref(),
+        )
+        .await;
+        self.received.record(request.into_received());
+        response
+    }
+
+    pub fn add_matcher(
+        &mut self,
+        m: impl StreamingMatcher<T> + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.matchers.push(Arc::new(StreamingMatch {
+            matcher: Box::new(m),
+        }));
+        self
+    }
+
+    /// Respond with a `StreamingResponder`, an `AsyncStreamingResponder` or a wrapper such as
+    /// [`Delayed`] around one.
+    pub fn response<M>(&mut self, r: impl IntoStreamingResponder<T, U, M>) -> &mut Self {
+        self.response = r.into_boxed();
+        self
+    }
+}
 
-        let mut stream = request.into_inner();
-        while let Ok(message) = stream.message().await {
-            if let Err(e) = tx.send(message) {
-                // If we fail to send to matchers it means none of the matchers cared about the
-                // request body. Just metadata!
-                trace!("Message broadcast failed, all matchers must be metadata matchers");
-                break;
+/// Read a bidirectional stream to the end on its own task, passing the messages on as they
+/// arrive. The responder doesn't have to read the messages, or can stop early, this way the
+/// request is still checked against the matchers and recorded once the stream ends.
+fn read_bidir_stream<T>(
+    request: Request<Streaming<T>>,
+    matchers: Vec<Arc<StreamingMatch<T>>>,
+    calls: CallRecorder,
+    received: RequestLog<T>,
+) -> RequestStream<T>
+where
+    T: Clone + Debug + Send + Sync + 'static,
+{
+    let (tx, rx) = futures::channel::mpsc::unbounded();
+    let messages = tx.clone();
+    let request = StreamRequest::receive_with(request, move |message: &T| {
+        let _ = messages.unbounded_send(Ok(message.clone()));
+    });
+    tokio::spawn(async move {
+        let request = request.await;
+        for matcher in matchers {
+            if !matcher
+                .request_matches(
+                    &request.metadata,
+                    request.trailers.as_ref(),
+                    &request.stream,
+                )
+                .await
+            {
+                calls.mismatch(matcher.description(), request.describe());
             }
         }
+        // The request is checked and recorded before the responder sees the end of the stream,
+        // so it's all there by the time the server closes its side. Any error is passed on as
+        // the last item.
+        let error = request.stream.error.clone();
+        received.record(request.into_received());
+        if let Some(status) = error {
+            let _ = tx.unbounded_send(Err(status));
+        }
+    });
+    Box::pin(rx)
+}
 
-        std::mem::drop(tx);
+impl<T, U> BidirStreamMethodMock<T, U>
+where
+    T: Clone + Send + 'static,
+{
+    pub fn process_request(
+        &self,
+        request: Request<Streaming<T>>,
+    ) -> Result<Response<ResponseStream<U>>, Status>
+    where
+        T: Debug + Sync,
+    {
+        self.record_header_mismatches(request.metadata());
+        self.respond(request)
+    }
 
-        while let Some(checked) = matcher_results.next().await {
-            matches &= checked;
-        }
+    /// The matchers which only look at the headers. The headers are here as soon as the call
+    /// comes in, so these are checked straight away whatever the responder does with the stream.
+    fn header_matchers(&self) -> impl Iterator<Item = &Arc<StreamingMatch<T>>> {
+        self.matchers
+            .iter()
+            .filter(|x| !x.checks_messages() && !x.checks_trailers())
+    }
 
-        let trailers = stream.trailers().await;
-        match trailers {
-            Ok(Some(map)) => {
-                for (matcher, og) in self.matchers.iter().zip(metadata_matches.iter()) {
-                    // TODO this is horribly wrong but oh well!
-                    matches &= *og || matcher.metadata_matches(&map, true);
-                }
-            }
-            _ => {
-                for og in &metadata_matches {
-                    matches &= *og;
-                }
+    fn header_matches(&self, metadata: &MetadataMap) -> bool {
+        self.header_matchers()
+            .all(|x| x.request_metadata_matches(metadata, None))
+    }
+
+    fn record_header_mismatches(&self, metadata: &MetadataMap) {
+        for matcher in self.header_matchers() {
+            if !matcher.request_metadata_matches(metadata, None) {
+                self.calls
+                    .mismatch(matcher.description(), format!("metadata: {:?}", metadata));
             }
         }
+    }
+
+    fn respond(&self, request: Request<Streaming<T>>) -> Result<Response<ResponseStream<U>>, Status>
+    where
+        T: Debug + Sync,
+    {
+        self.calls.called();
+        let metadata = request.metadata().clone();
+        // The rest of the matchers are checked with the whole request once the stream ends
+        let matchers = self
+            .matchers
+            .iter()
+            .filter(|x| x.checks_messages() || x.checks_trailers())
+            .cloned()
+            .collect();
+        let messages =
+            read_bidir_stream(request, matchers, self.calls.clone(), self.received.clone());
+        self.response.respond(&metadata, messages)
+    }
 
-        //
+    pub fn add_matcher(
+        &mut self,
+        m: impl StreamingMatcher<T> + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.matchers.push(Arc::new(StreamingMatch {
+            matcher: Box::new(m),
+        }));
+        self
+    }
 
-        //    for matcher in &self.matchers {
-        //        assert!(matcher.matches(&request))
-        //    }
-        //    self.response.respond(request)
-        todo!()
+    pub fn response(
+        &mut self,
+        r: impl BidirStreamResponder<T, U> + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.response = Box::new(r);
+        self
     }
 }
 
@@ -157,19 +767,56 @@ pub struct StreamingMatch<T: Clone + Send + 'static> {
 }
 
 impl<T: Clone + Send + 'static> StreamingMatch<T> {
+    #[inline(always)]
+    pub fn description(&self) -> String {
+        self.matcher.description()
+    }
+
+    #[inline(always)]
+    pub fn checks_messages(&self) -> bool {
+        self.matcher.checks_messages()
+    }
+
+    #[inline(always)]
+    pub fn checks_trailers(&self) -> bool {
+        self.matcher.checks_trailers()
+    }
+
     #[inline(always)]
     pub fn metadata_matches(&self, metadata: &MetadataMap, is_trailer: bool) -> bool {
         self.matcher.metadata_matches(metadata, is_trailer)
     }
 
-    pub fn single_match(&self, value: &T) -> bool {
+    #[inline(always)]
+    pub fn request_metadata_matches(
+        &self,
+        header: &MetadataMap,
+        trailers: Option<&MetadataMap>,
+    ) -> bool {
+        self.matcher.request_metadata_matches(header, trailers)
+    }
+
+    pub fn single_match(&self, _value: &T) -> bool {
         unreachable!("single_match should not be called on the StreamingMatch wrapper type");
     }
 
     #[inline(always)]
-    pub async fn stream_match(&self, rx: broadcast::Receiver<Option<T>>) -> bool {
+    pub async fn stream_match(&self, rx: broadcast::Receiver<Option<Result<T, Status>>>) -> bool {
         self.matcher.stream_match(rx).await
     }
+
+    #[inline(always)]
+    pub async fn request_matches(
+        &self,
+        header: &MetadataMap,
+        trailers: Option<&MetadataMap>,
+        stream: &ReceivedStream<T>,
+    ) -> bool
+    where
+        T: Sync,
+    {
+        self.matcher.request_matches(header, trailers, stream).await
+    }
 }
 
 pub struct Match<T> {
@@ -177,6 +824,11 @@ pub struct Match<T> {
 }
 
 impl<T> Match<T> {
+    #[inline(always)]
+    pub fn description(&self) -> String {
+        self.matcher.description()
+    }
+
     #[inline(always)]
     pub fn matches(&self, request: &Request<T>) -> bool {
         self.matcher.matches(request)
@@ -189,59 +841,1298 @@ impl<T> From<Box<dyn Matcher<T> + Send + Sync>> for Match<T> {
     }
 }
 
+/// Where in the request a metadata matcher looks.
 #[derive(Debug, Copy, Clone)]
-pub(crate) enum MetadataLocation {
+pub enum MetadataLocation {
+    /// The headers or the trailers, only streaming requests have trailers
     Any,
     Header,
     Trailer,
 }
 
-pub struct MetadataExistsMatcher {
-    key: String,
-    area: MetadataLocation,
-}
+impl MetadataLocation {
+    /// Whether the metadata being checked is in this location.
+    fn includes(&self, is_trailer: bool) -> bool {
+        matches!(
+            (self, is_trailer),
+            (Self::Any, _) | (Self::Header, false) | (Self::Trailer, true)
+        )
+    }
 
-impl MetadataExistsMatcher {
-    pub fn new(key: String) -> Self {
-        Self {
-            key,
-            area: MetadataLocation::Any,
+    /// Whether `check` passes for the metadata in this location. Unary requests don't have
+    /// trailers so they're `None`.
+    fn check(
+        &self,
+        header: &MetadataMap,
+        trailers: Option<&MetadataMap>,
+        check: impl Fn(&MetadataMap) -> bool,
+    ) -> bool {
+        let in_trailers = || trailers.map(&check).unwrap_or(false);
+        match self {
+            Self::Any => check(header) || in_trailers(),
+            Self::Header => check(header),
+            Self::Trailer => in_trailers(),
         }
     }
+}
 
-    pub fn header(key: String) -> Self {
-        Self {
-            key,
-            area: MetadataLocation::Header,
-        }
+/// A check on the values sent for a metadata key, see [`MetadataMatcher`].
+pub trait MetadataCheck {
+    /// Whether the headers or trailers have a value for the key which passes.
+    fn values_match(&self, metadata: &MetadataMap, key: &str) -> bool;
+
+    /// Whether the matcher passes when no value does, i.e. when checking the key is absent.
+    fn negated(&self) -> bool {
+        false
     }
 
-    pub fn trailer(key: String) -> Self {
+    /// Describes the matcher in verification reports.
+    fn describe(&self, key: &str, location: MetadataLocation) -> String;
+}
+
+/// Matches the values sent for a metadata key in the headers, the trailers or either. The
+/// metadata matchers are all this with a different [`MetadataCheck`], i.e.
+/// [`MetadataEqMatcher`] is a `MetadataMatcher<ValueEquals>`.
+pub struct MetadataMatcher<P> {
+    key: String,
+    location: MetadataLocation,
+    check: P,
+}
+
+impl<P: MetadataCheck> MetadataMatcher<P> {
+    /// Match with a custom check on the values for the key.
+    pub fn at(location: MetadataLocation, key: String, check: P) -> Self {
         Self {
             key,
-            area: MetadataLocation::Trailer,
+            location,
+            check,
         }
     }
+
+    fn location_matches(&self, header: &MetadataMap, trailers: Option<&MetadataMap>) -> bool {
+        let found = self
+            .location
+            .check(header, trailers, |x| self.check.values_match(x, &self.key));
+        found != self.check.negated()
+    }
 }
 
-impl<T> Matcher<T> for MetadataExistsMatcher {
+impl<T, P: MetadataCheck> Matcher<T> for MetadataMatcher<P> {
     fn matches(&self, request: &Request<T>) -> bool {
-        request.metadata().contains_key(&self.key)
+        self.location_matches(request.metadata(), None)
+    }
+
+    fn description(&self) -> String {
+        self.check.describe(&self.key, self.location)
     }
 }
 
 #[async_trait::async_trait]
-impl<T: Clone + Send + 'static> StreamingMatcher<T> for MetadataExistsMatcher {
+impl<T, P> StreamingMatcher<T> for MetadataMatcher<P>
+where
+    T: Clone + Send + 'static,
+    P: MetadataCheck + Send + Sync,
+{
+    fn description(&self) -> String {
+        self.check.describe(&self.key, self.location)
+    }
+
+    fn checks_messages(&self) -> bool {
+        false
+    }
+
+    fn checks_trailers(&self) -> bool {
+        !matches!(self.location, MetadataLocation::Header)
+    }
+
     fn metadata_matches(&self, metadata: &MetadataMap, is_trailer: bool) -> bool {
-        match (self.area, is_trailer) {
-            (MetadataLocation::Any, _)
-            | (MetadataLocation::Header, false)
-            | (MetadataLocation::Trailer, true) => metadata.contains_key(&self.key),
-            _ => true,
-        }
+        !self.location.includes(is_trailer)
+            || self.check.values_match(metadata, &self.key) != self.check.negated()
+    }
+
+    fn request_metadata_matches(
+        &self,
+        header: &MetadataMap,
+        trailers: Option<&MetadataMap>,
+    ) -> bool {
+        self.location_matches(header, trailers)
     }
 
-    async fn stream_match(&self, mut rx: broadcast::Receiver<Option<T>>) -> bool {
+    async fn stream_match(&self, _rx: broadcast::Receiver<Option<Result<T, Status>>>) -> bool {
         true
     }
 }
+
+/// The key is sent, whatever the value.
+pub struct KeyExists;
+
+impl MetadataCheck for KeyExists {
+    fn values_match(&self, metadata: &MetadataMap, key: &str) -> bool {
+        metadata.contains_key(key)
+    }
+
+    fn describe(&self, key: &str, location: MetadataLocation) -> String {
+        format!("metadata `{}` exists in {:?}", key, location)
+    }
+}
+
+/// The key isn't sent, i.e. to check internal headers aren't leaked.
+pub struct KeyAbsent;
+
+impl MetadataCheck for KeyAbsent {
+    fn values_match(&self, metadata: &MetadataMap, key: &str) -> bool {
+        metadata.contains_key(key)
+    }
+
+    fn negated(&self) -> bool {
+        true
+    }
+
+    fn describe(&self, key: &str, location: MetadataLocation) -> String {
+        format!("metadata `{}` is absent from {:?}", key, location)
+    }
+}
+
+/// A value for the key is equal to the given one.
+pub struct ValueEquals(pub String);
+
+impl MetadataCheck for ValueEquals {
+    fn values_match(&self, metadata: &MetadataMap, key: &str) -> bool {
+        ascii_values(metadata, key).any(|x| x == self.0)
+    }
+
+    fn describe(&self, key: &str, location: MetadataLocation) -> String {
+        format!("metadata `{}` equals {:?} in {:?}", key, self.0, location)
+    }
+}
+
+/// A value for the key matches the regex.
+pub struct ValueMatches(pub Regex);
+
+impl MetadataCheck for ValueMatches {
+    fn values_match(&self, metadata: &MetadataMap, key: &str) -> bool {
+        ascii_values(metadata, key).any(|x| self.0.is_match(x))
+    }
+
+    fn describe(&self, key: &str, location: MetadataLocation) -> String {
+        format!("metadata `{}` matches /{}/ in {:?}", key, self.0, location)
+    }
+}
+
+/// A value for the key starts with the prefix.
+pub struct ValueStartsWith(pub String);
+
+impl MetadataCheck for ValueStartsWith {
+    fn values_match(&self, metadata: &MetadataMap, key: &str) -> bool {
+        ascii_values(metadata, key).any(|x| x.starts_with(&self.0))
+    }
+
+    fn describe(&self, key: &str, location: MetadataLocation) -> String {
+        format!(
+            "metadata `{}` starts with {:?} in {:?}",
+            key, self.0, location
+        )
+    }
+}
+
+/// A value for the binary key is equal to the given bytes once decoded.
+pub struct BinValueEquals(pub Vec<u8>);
+
+impl MetadataCheck for BinValueEquals {
+    fn values_match(&self, metadata: &MetadataMap, key: &str) -> bool {
+        metadata
+            .get_all_bin(key)
+            .iter()
+            .filter_map(|x| x.to_bytes().ok())
+            .any(|x| x == self.0)
+    }
+
+    fn describe(&self, key: &str, location: MetadataLocation) -> String {
+        format!("metadata `{}` equals {:?} in {:?}", key, self.0, location)
+    }
+}
+
+pub type MetadataExistsMatcher = MetadataMatcher<KeyExists>;
+
+impl MetadataExistsMatcher {
+    pub fn new(key: String) -> Self {
+        Self::at(MetadataLocation::Any, key, KeyExists)
+    }
+
+    pub fn header(key: String) -> Self {
+        Self::at(MetadataLocation::Header, key, KeyExists)
+    }
+
+    pub fn trailer(key: String) -> Self {
+        Self::at(MetadataLocation::Trailer, key, KeyExists)
+    }
+}
+
+/// Matches when the metadata key isn't sent, i.e. to check internal headers aren't leaked.
+pub type MetadataAbsentMatcher = MetadataMatcher<KeyAbsent>;
+
+impl MetadataAbsentMatcher {
+    pub fn new(key: String) -> Self {
+        Self::at(MetadataLocation::Any, key, KeyAbsent)
+    }
+
+    pub fn header(key: String) -> Self {
+        Self::at(MetadataLocation::Header, key, KeyAbsent)
+    }
+
+    pub fn trailer(key: String) -> Self {
+        Self::at(MetadataLocation::Trailer, key, KeyAbsent)
+    }
+}
+
+/// Matches when the metadata has a value for the key equal to the given one.
+pub type MetadataEqMatcher = MetadataMatcher<ValueEquals>;
+
+impl MetadataEqMatcher {
+    pub fn new(key: String, value: String) -> Self {
+        Self::at(MetadataLocation::Any, key, ValueEquals(value))
+    }
+
+    pub fn header(key: String, value: String) -> Self {
+        Self::at(MetadataLocation::Header, key, ValueEquals(value))
+    }
+
+    pub fn trailer(key: String, value: String) -> Self {
+        Self::at(MetadataLocation::Trailer, key, ValueEquals(value))
+    }
+}
+
+/// Matches when the metadata has a value for the key matching the regex.
+pub type MetadataRegexMatcher = MetadataMatcher<ValueMatches>;
+
+impl MetadataRegexMatcher {
+    pub fn new(key: String, regex: &str) -> Result<Self, regex::Error> {
+        Ok(Self::at(
+            MetadataLocation::Any,
+            key,
+            ValueMatches(Regex::new(regex)?),
+        ))
+    }
+
+    pub fn header(key: String, regex: &str) -> Result<Self, regex::Error> {
+        Ok(Self::at(
+            MetadataLocation::Header,
+            key,
+            ValueMatches(Regex::new(regex)?),
+        ))
+    }
+
+    pub fn trailer(key: String, regex: &str) -> Result<Self, regex::Error> {
+        Ok(Self::at(
+            MetadataLocation::Trailer,
+            key,
+            ValueMatches(Regex::new(regex)?),
+        ))
+    }
+}
+
+/// Matches when the metadata has a value for the key starting with the prefix, i.e. a `Bearer `
+/// authorization header.
+pub type MetadataPrefixMatcher = MetadataMatcher<ValueStartsWith>;
+
+impl MetadataPrefixMatcher {
+    pub fn new(key: String, prefix: String) -> Self {
+        Self::at(MetadataLocation::Any, key, ValueStartsWith(prefix))
+    }
+
+    pub fn header(key: String, prefix: String) -> Self {
+        Self::at(MetadataLocation::Header, key, ValueStartsWith(prefix))
+    }
+
+    pub fn trailer(key: String, prefix: String) -> Self {
+        Self::at(MetadataLocation::Trailer, key, ValueStartsWith(prefix))
+    }
+}
+
+/// Matches when the binary metadata has a value for the key equal to the given bytes. Binary
+/// keys end in `-bin` and the values are compared after decoding them.
+pub type MetadataBinEqMatcher = MetadataMatcher<BinValueEquals>;
+
+impl MetadataBinEqMatcher {
+    pub fn new(key: String, value: Vec<u8>) -> Self {
+        Self::at(MetadataLocation::Any, key, BinValueEquals(value))
+    }
+
+    pub fn header(key: String, value: Vec<u8>) -> Self {
+        Self::at(MetadataLocation::Header, key, BinValueEquals(value))
+    }
+
+    pub fn trailer(key: String, value: Vec<u8>) -> Self {
+        Self::at(MetadataLocation::Trailer, key, BinValueEquals(value))
+    }
+}
+
+/// Matches the deadline the client set with `grpc-timeout`, i.e. that it set one between 1s and
+/// 5s with `DeadlineMatcher::new(Duration::from_secs(1)..=Duration::from_secs(5))`.
+pub struct DeadlineMatcher {
+    /// The range the timeout has to be in, `None` if the client shouldn't set a deadline
+    range: Option<(Bound<Duration>, Bound<Duration>)>,
+}
+
+impl DeadlineMatcher {
+    pub fn new(range: impl RangeBounds<Duration>) -> Self {
+        Self {
+            range: Some((range.start_bound().cloned(), range.end_bound().cloned())),
+        }
+    }
+
+    /// The client set a deadline, whatever it is.
+    pub fn any() -> Self {
+        Self::new(..)
+    }
+
+    /// The client didn't set a deadline.
+    pub fn none() -> Self {
+        Self { range: None }
+    }
+
+    fn describe(&self) -> String {
+        match &self.range {
+            Some(range) => format!("deadline in {:?}", range),
+            None => "no deadline".to_string(),
+        }
+    }
+
+    fn timeout_matches(&self, header: &MetadataMap) -> bool {
+        match (&self.range, grpc_timeout(header)) {
+            (Some(range), Some(timeout)) => range.contains(&timeout),
+            (None, None) => true,
+            _ => false,
+        }
+    }
+}
+
+impl<T> Matcher<T> for DeadlineMatcher {
+    fn matches(&self, request: &Request<T>) -> bool {
+        self.timeout_matches(request.metadata())
+    }
+
+    fn description(&self) -> String {
+        self.describe()
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: Clone + Send + 'static> StreamingMatcher<T> for DeadlineMatcher {
+    fn description(&self) -> String {
+        self.describe()
+    }
+
+    fn checks_messages(&self) -> bool {
+        false
+    }
+
+    fn checks_trailers(&self) -> bool {
+        false
+    }
+
+    fn metadata_matches(&self, metadata: &MetadataMap, is_trailer: bool) -> bool {
+        is_trailer || self.timeout_matches(metadata)
+    }
+
+    async fn stream_match(&self, _rx: broadcast::Receiver<Option<Result<T, Status>>>) -> bool {
+        true
+    }
+}
+
+/// The ASCII values for the key, skipping any which aren't valid strings.
+fn ascii_values<'a>(metadata: &'a MetadataMap, key: &str) -> impl Iterator<Item = &'a str> {
+    metadata.get_all(key).iter().filter_map(|x| x.to_str().ok())
+}
+
+/// Matches requests whose message is equal to the given one.
+pub struct BodyEq<T>(pub T);
+
+impl<T> Matcher<T> for BodyEq<T>
+where
+    T: PartialEq + Debug,
+{
+    fn matches(&self, request: &Request<T>) -> bool {
+        request.get_ref() == &self.0
+    }
+
+    fn description(&self) -> String {
+        format!("body equals {:?}", self.0)
+    }
+}
+
+/// Matches requests whose message passes a predicate, see [`predicate`].
+pub struct Predicate<F> {
+    predicate: F,
+    description: String,
+}
+
+/// Match requests with a closure over the message:
+///
+/// ```
+/// # use tonic_mock::matchers::predicate;
+/// let matcher = predicate(|msg: &String| msg.starts_with("hello")).describe("greeting");
+/// ```
+pub fn predicate<T, F>(predicate: F) -> Predicate<F>
+where
+    F: Fn(&T) -> bool,
+{
+    Predicate {
+        predicate,
+        description: "body matches predicate".to_string(),
+    }
+}
+
+impl<F> Predicate<F> {
+    /// Describe the predicate in verification reports.
+    pub fn describe(mut self, description: impl Into<String>) -> Self {
+        self.description = description.into();
+        self
+    }
+}
+
+impl<T, F> Matcher<T> for Predicate<F>
+where
+    F: Fn(&T) -> bool,
+{
+    fn matches(&self, request: &Request<T>) -> bool {
+        (self.predicate)(request.get_ref())
+    }
+
+    fn description(&self) -> String {
+        self.description.clone()
+    }
+}
+
+/// The value of a field in an encoded protobuf message, as it appears on the wire.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FieldValue {
+    /// The integer types other than the fixed ones, bools and enums
+    Varint(u64),
+    Fixed32(u32),
+    Fixed64(u64),
+    /// Strings, bytes and messages
+    Bytes(Vec<u8>),
+}
+
+impl FieldValue {
+    fn is_default(&self) -> bool {
+        match self {
+            Self::Varint(x) | Self::Fixed64(x) => *x == 0,
+            Self::Fixed32(x) => *x == 0,
+            Self::Bytes(x) => x.is_empty(),
+        }
+    }
+}
+
+/// Matches requests where a field of the message has the given value. This works on the encoded
+/// message so it can be used with any prost message. The field is given by the path of field
+/// numbers from the proto definitions, so for `Point.latitude` it's `[1]` and for
+/// `Rectangle.lo.latitude` it's `[1, 1]`.
+///
+/// Unset fields match their default value, and if a field appears more than once the last value
+/// is used, the same as when decoding the message.
+pub struct FieldEq {
+    path: Vec<u32>,
+    value: FieldValue,
+}
+
+impl FieldEq {
+    pub fn new(path: impl Into<Vec<u32>>, value: FieldValue) -> Self {
+        Self {
+            path: path.into(),
+            value,
+        }
+    }
+
+    /// An `int32`, `int64`, `uint32`, `uint64` or enum field.
+    pub fn int(path: impl Into<Vec<u32>>, value: i64) -> Self {
+        Self::new(path, FieldValue::Varint(value as u64))
+    }
+
+    /// A `sint32` or `sint64` field.
+    pub fn sint(path: impl Into<Vec<u32>>, value: i64) -> Self {
+        Self::new(
+            path,
+            FieldValue::Varint(((value << 1) ^ (value >> 63)) as u64),
+        )
+    }
+
+    pub fn bool(path: impl Into<Vec<u32>>, value: bool) -> Self {
+        Self::new(path, FieldValue::Varint(value as u64))
+    }
+
+    pub fn double(path: impl Into<Vec<u32>>, value: f64) -> Self {
+        Self::new(path, FieldValue::Fixed64(value.to_bits()))
+    }
+
+    pub fn float(path: impl Into<Vec<u32>>, value: f32) -> Self {
+        Self::new(path, FieldValue::Fixed32(value.to_bits()))
+    }
+
+    pub fn string(path: impl Into<Vec<u32>>, value: &str) -> Self {
+        Self::new(path, FieldValue::Bytes(value.as_bytes().to_vec()))
+    }
+
+    pub fn bytes(path: impl Into<Vec<u32>>, value: impl Into<Vec<u8>>) -> Self {
+        Self::new(path, FieldValue::Bytes(value.into()))
+    }
+
+    fn matches_encoded(&self, buf: &[u8]) -> bool {
+        match find_field(buf, &self.path) {
+            Ok(Some(value)) => value == self.value,
+            Ok(None) => self.value.is_default(),
+            Err(e) => {
+                error!("Failed to decode message for field matching: {}", e);
+                false
+            }
+        }
+    }
+}
+
+impl<T: prost::Message> Matcher<T> for FieldEq {
+    fn matches(&self, request: &Request<T>) -> bool {
+        self.matches_encoded(&request.get_ref().encode_to_vec())
+    }
+
+    fn description(&self) -> String {
+        format!("field {:?} equals {:?}", self.path, self.value)
+    }
+}
+
+/// Find the last value of the field at `path` in an encoded message. Embedded messages which
+/// appear more than once are merged, so their encodings are concatenated before looking further.
+fn find_field(mut buf: &[u8], path: &[u32]) -> Result<Option<FieldValue>, DecodeError> {
+    let Some((field, rest)) = path.split_first() else {
+        return Ok(None);
+    };
+    let mut found = None;
+    let mut nested = vec![];
+    while buf.has_remaining() {
+        let (tag, wire_type) = decode_key(&mut buf)?;
+        let value = match wire_type {
+            WireType::Varint => FieldValue::Varint(decode_varint(&mut buf)?),
+            WireType::SixtyFourBit if buf.remaining() >= 8 => FieldValue::Fixed64(buf.get_u64_le()),
+            WireType::ThirtyTwoBit if buf.remaining() >= 4 => FieldValue::Fixed32(buf.get_u32_le()),
+            WireType::LengthDelimited => {
+                let len = decode_varint(&mut buf)? as usize;
+                if buf.remaining() < len {
+                    return Err(DecodeError::new("buffer underflow"));
+                }
+                let (value, remaining) = buf.split_at(len);
+                buf = remaining;
+                FieldValue::Bytes(value.to_vec())
+            }
+            WireType::SixtyFourBit | WireType::ThirtyTwoBit => {
+                return Err(DecodeError::new("buffer underflow"))
+            }
+            WireType::StartGroup | WireType::EndGroup => {
+                return Err(DecodeError::new("groups aren't supported"))
+            }
+        };
+        if tag == *field {
+            match value {
+                FieldValue::Bytes(bytes) if !rest.is_empty() => nested.extend(bytes),
+                value => found = Some(value),
+            }
+        }
+    }
+    if rest.is_empty() {
+        Ok(found)
+    } else {
+        find_field(&nested, rest)
+    }
+}
+
+impl<T, M> Matcher<T> for Box<M>
+where
+    M: Matcher<T> + ?Sized,
+{
+    fn matches(&self, request: &Request<T>) -> bool {
+        (**self).matches(request)
+    }
+
+    fn description(&self) -> String {
+        (**self).description()
+    }
+}
+
+#[async_trait::async_trait]
+impl<T, M> StreamingMatcher<T> for Box<M>
+where
+    T: Clone + Send + 'static,
+    M: StreamingMatcher<T> + Send + Sync + ?Sized,
+{
+    fn description(&self) -> String {
+        (**self).description()
+    }
+
+    fn checks_messages(&self) -> bool {
+        (**self).checks_messages()
+    }
+
+    fn checks_trailers(&self) -> bool {
+        (**self).checks_trailers()
+    }
+
+    fn metadata_matches(&self, metadata: &MetadataMap, is_trailer: bool) -> bool {
+        (**self).metadata_matches(metadata, is_trailer)
+    }
+
+    fn request_metadata_matches(
+        &self,
+        header: &MetadataMap,
+        trailers: Option<&MetadataMap>,
+    ) -> bool {
+        (**self).request_metadata_matches(header, trailers)
+    }
+
+    fn single_match(&self, value: &T) -> bool {
+        (**self).single_match(value)
+    }
+
+    async fn stream_match(&self, rx: broadcast::Receiver<Option<Result<T, Status>>>) -> bool {
+        (**self).stream_match(rx).await
+    }
+
+    async fn request_matches(
+        &self,
+        header: &MetadataMap,
+        trailers: Option<&MetadataMap>,
+        stream: &ReceivedStream<T>,
+    ) -> bool
+    where
+        T: Sync,
+    {
+        (**self).request_matches(header, trailers, stream).await
+    }
+}
+
+/// The matchers being combined by [`AllOf`] or [`AnyOf`]. This is implemented for `Vec`s of
+/// matchers and for pairs, so matchers of different types can be combined with `and` and `or`.
+pub trait MatcherSet<T> {
+    fn matchers(&self) -> Vec<&dyn Matcher<T>>;
+}
+
+impl<T, M: Matcher<T>> MatcherSet<T> for Vec<M> {
+    fn matchers(&self) -> Vec<&dyn Matcher<T>> {
+        self.iter().map(|x| x as &dyn Matcher<T>).collect()
+    }
+}
+
+impl<T, A: Matcher<T>, B: Matcher<T>> MatcherSet<T> for (A, B) {
+    fn matchers(&self) -> Vec<&dyn Matcher<T>> {
+        vec![&self.0, &self.1]
+    }
+}
+
+/// The streaming version of [`MatcherSet`].
+pub trait StreamingMatcherSet<T: Clone + Send + 'static> {
+    fn matchers(&self) -> Vec<&(dyn StreamingMatcher<T> + Send + Sync)>;
+}
+
+impl<T, M> StreamingMatcherSet<T> for Vec<M>
+where
+    T: Clone + Send + 'static,
+    M: StreamingMatcher<T> + Send + Sync,
+{
+    fn matchers(&self) -> Vec<&(dyn StreamingMatcher<T> + Send + Sync)> {
+        self.iter()
+            .map(|x| x as &(dyn StreamingMatcher<T> + Send + Sync))
+            .collect()
+    }
+}
+
+impl<T, A, B> StreamingMatcherSet<T> for (A, B)
+where
+    T: Clone + Send + 'static,
+    A: StreamingMatcher<T> + Send + Sync,
+    B: StreamingMatcher<T> + Send + Sync,
+{
+    fn matchers(&self) -> Vec<&(dyn StreamingMatcher<T> + Send + Sync)> {
+        vec![&self.0, &self.1]
+    }
+}
+
+/// Matches when all of the matchers match, see [`all_of`].
+pub struct AllOf<M>(pub M);
+
+/// Matches when any of the matchers match, see [`any_of`].
+///
+/// For streaming requests the mocks check the whole request at once with `request_matches`, so
+/// metadata and message matchers can be mixed. Checked on their own, the metadata or messages
+/// pass if they pass for any of the matchers which look at them.
+pub struct AnyOf<M>(pub M);
+
+/// Matches when the matcher doesn't.
+///
+/// For streaming requests the mocks check the whole request at once with `request_matches`, so
+/// this negates the check of the metadata and messages together. Checked on their own, the
+/// messages check is negated, or the metadata check if the matcher only looks at the metadata.
+pub struct Not<M>(pub M);
+
+/// Match when all of the matchers match:
+///
+/// ```
+/// # use tonic_mock::matchers::{all_of, MetadataExistsMatcher};
+/// let matcher = all_of(vec![
+///     MetadataExistsMatcher::new("x-tenant".into()),
+///     MetadataExistsMatcher::new("x-org".into()),
+/// ]);
+/// ```
+pub fn all_of<M>(matchers: Vec<M>) -> AllOf<Vec<M>> {
+    AllOf(matchers)
+}
+
+/// Match when any of the matchers match.
+pub fn any_of<M>(matchers: Vec<M>) -> AnyOf<Vec<M>> {
+    AnyOf(matchers)
+}
+
+/// Match when the matcher doesn't.
+pub fn not<M>(matcher: M) -> Not<M> {
+    Not(matcher)
+}
+
+/// Combine matchers with `and`, `or` and `not`, for both unary and streaming matchers:
+///
+/// ```
+/// # use tonic_mock::matchers::{MatcherExt, MetadataExistsMatcher};
+/// let matcher = MetadataExistsMatcher::new("x-tenant".into())
+///     .or(MetadataExistsMatcher::new("x-org".into()))
+///     .and(MetadataExistsMatcher::new("x-debug".into()).not());
+/// ```
+///
+/// It's implemented for the matchers in this crate. There's nothing to implement, so a matcher
+/// of your own can have it with `impl MatcherExt for MyMatcher {}`.
+pub trait MatcherExt: Sized {
+    fn and<M>(self, other: M) -> AllOf<(Self, M)> {
+        AllOf((self, other))
+    }
+
+    fn or<M>(self, other: M) -> AnyOf<(Self, M)> {
+        AnyOf((self, other))
+    }
+
+    fn not(self) -> Not<Self> {
+        Not(self)
+    }
+}
+
+impl<P> MatcherExt for MetadataMatcher<P> {}
+impl MatcherExt for DeadlineMatcher {}
+impl<T> MatcherExt for BodyEq<T> {}
+impl<F> MatcherExt for Predicate<F> {}
+impl MatcherExt for FieldEq {}
+impl<S> MatcherExt for AllOf<S> {}
+impl<S> MatcherExt for AnyOf<S> {}
+impl<M> MatcherExt for Not<M> {}
+impl MatcherExt for MessageCount {}
+impl<T> MatcherExt for SequenceEq<T> {}
+impl<F> MatcherExt for Contains<F> {}
+impl<F> MatcherExt for OrderedBy<F> {}
+impl<F> MatcherExt for FirstMessage<F> {}
+impl<F> MatcherExt for LastMessage<F> {}
+impl MatcherExt for StreamEnd {}
+
+fn describe_all(descriptions: impl Iterator<Item = String>) -> String {
+    descriptions.collect::<Vec<_>>().join(", ")
+}
+
+/// All the messages a client sent on a stream and how the stream ended.
+pub struct ReceivedStream<T> {
+    pub messages: Vec<T>,
+    /// The error the stream ended with, `None` if the client half-closed it
+    pub error: Option<Status>,
+}
+
+impl<T: Clone> ReceivedStream<T> {
+    /// A channel with all the messages already sent, so the stream can be replayed to each
+    /// matcher's `stream_match`. This is sized to fit the stream so the matchers can't lag
+    /// behind.
+    pub fn replay(&self) -> broadcast::Receiver<Option<Result<T, Status>>> {
+        let (tx, rx) = broadcast::channel(self.messages.len() + 1);
+        for message in &self.messages {
+            let _ = tx.send(Some(Ok(message.clone())));
+        }
+        let _ = tx.send(self.error.clone().map(Err));
+        rx
+    }
+}
+
+/// Read all the messages in the stream, `None` if the channel lagged and messages were lost.
+async fn receive_all<T: Clone>(
+    mut rx: broadcast::Receiver<Option<Result<T, Status>>>,
+) -> Option<ReceivedStream<T>> {
+    let mut messages = vec![];
+    loop {
+        let error = match rx.recv().await {
+            Ok(Some(Ok(message))) => {
+                messages.push(message);
+                continue;
+            }
+            Ok(None) => None,
+            Ok(Some(Err(status))) => Some(status),
+            Err(RecvError::Closed) => Some(Status::unknown("stream closed without half-closing")),
+            Err(RecvError::Lagged(lag)) => {
+                error!("Checking channel lagged by {} messages", lag);
+                return None;
+            }
+        };
+        return Some(ReceivedStream { messages, error });
+    }
+}
+
+impl<T, S: MatcherSet<T>> Matcher<T> for AllOf<S> {
+    fn matches(&self, request: &Request<T>) -> bool {
+        self.0.matchers().iter().all(|x| x.matches(request))
+    }
+
+    fn description(&self) -> String {
+        let matchers = self.0.matchers();
+        format!(
+            "all of [{}]",
+            describe_all(matchers.iter().map(|x| x.description()))
+        )
+    }
+}
+
+#[async_trait::async_trait]
+impl<T, S> StreamingMatcher<T> for AllOf<S>
+where
+    T: Clone + Send + Sync + 'static,
+    S: StreamingMatcherSet<T> + Send + Sync,
+{
+    fn description(&self) -> String {
+        let matchers = self.0.matchers();
+        format!(
+            "all of [{}]",
+            describe_all(matchers.iter().map(|x| x.description()))
+        )
+    }
+
+    fn checks_messages(&self) -> bool {
+        self.0.matchers().iter().any(|x| x.checks_messages())
+    }
+
+    fn checks_trailers(&self) -> bool {
+        self.0.matchers().iter().any(|x| x.checks_trailers())
+    }
+
+    fn metadata_matches(&self, metadata: &MetadataMap, is_trailer: bool) -> bool {
+        self.0
+            .matchers()
+            .iter()
+            .all(|x| x.metadata_matches(metadata, is_trailer))
+    }
+
+    fn request_metadata_matches(
+        &self,
+        header: &MetadataMap,
+        trailers: Option<&MetadataMap>,
+    ) -> bool {
+        self.0
+            .matchers()
+            .iter()
+            .all(|x| x.request_metadata_matches(header, trailers))
+    }
+
+    async fn stream_match(&self, rx: broadcast::Receiver<Option<Result<T, Status>>>) -> bool {
+        if !self.checks_messages() {
+            return true;
+        }
+        let Some(stream) = receive_all(rx).await else {
+            return false;
+        };
+        for matcher in self.0.matchers() {
+            if !matcher.stream_match(stream.replay()).await {
+                return false;
+            }
+        }
+        true
+    }
+
+    async fn request_matches(
+        &self,
+        header: &MetadataMap,
+        trailers: Option<&MetadataMap>,
+        stream: &ReceivedStream<T>,
+    ) -> bool
+    where
+        T: Sync,
+    {
+        for matcher in self.0.matchers() {
+            if !matcher.request_matches(header, trailers, stream).await {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl<T, S: MatcherSet<T>> Matcher<T> for AnyOf<S> {
+    fn matches(&self, request: &Request<T>) -> bool {
+        self.0.matchers().iter().any(|x| x.matches(request))
+    }
+
+    fn description(&self) -> String {
+        let matchers = self.0.matchers();
+        format!(
+            "any of [{}]",
+            describe_all(matchers.iter().map(|x| x.description()))
+        )
+    }
+}
+
+#[async_trait::async_trait]
+impl<T, S> StreamingMatcher<T> for AnyOf<S>
+where
+    T: Clone + Send + Sync + 'static,
+    S: StreamingMatcherSet<T> + Send + Sync,
+{
+    fn description(&self) -> String {
+        let matchers = self.0.matchers();
+        format!(
+            "any of [{}]",
+            describe_all(matchers.iter().map(|x| x.description()))
+        )
+    }
+
+    fn checks_messages(&self) -> bool {
+        self.0.matchers().iter().any(|x| x.checks_messages())
+    }
+
+    fn checks_trailers(&self) -> bool {
+        self.0.matchers().iter().any(|x| x.checks_trailers())
+    }
+
+    fn metadata_matches(&self, metadata: &MetadataMap, is_trailer: bool) -> bool {
+        let matchers = self.0.matchers();
+        let mut metadata_matchers = matchers.iter().filter(|x| !x.checks_messages()).peekable();
+        metadata_matchers.peek().is_none()
+            || metadata_matchers.any(|x| x.metadata_matches(metadata, is_trailer))
+    }
+
+    fn request_metadata_matches(
+        &self,
+        header: &MetadataMap,
+        trailers: Option<&MetadataMap>,
+    ) -> bool {
+        let matchers = self.0.matchers();
+        let mut metadata_matchers = matchers.iter().filter(|x| !x.checks_messages()).peekable();
+        metadata_matchers.peek().is_none()
+            || metadata_matchers.any(|x| x.request_metadata_matches(header, trailers))
+    }
+
+    async fn stream_match(&self, rx: broadcast::Receiver<Option<Result<T, Status>>>) -> bool {
+        if !self.checks_messages() {
+            return true;
+        }
+        let Some(stream) = receive_all(rx).await else {
+            return false;
+        };
+        for matcher in self.0.matchers().iter().filter(|x| x.checks_messages()) {
+            if matcher.stream_match(stream.replay()).await {
+                return true;
+            }
+        }
+        false
+    }
+
+    async fn request_matches(
+        &self,
+        header: &MetadataMap,
+        trailers: Option<&MetadataMap>,
+        stream: &ReceivedStream<T>,
+    ) -> bool
+    where
+        T: Sync,
+    {
+        for matcher in self.0.matchers() {
+            if matcher.request_matches(header, trailers, stream).await {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+impl<T, M: Matcher<T>> Matcher<T> for Not<M> {
+    fn matches(&self, request: &Request<T>) -> bool {
+        !self.0.matches(request)
+    }
+
+    fn description(&self) -> String {
+        format!("not ({})", self.0.description())
+    }
+}
+
+#[async_trait::async_trait]
+impl<T, M> StreamingMatcher<T> for Not<M>
+where
+    T: Clone + Send + 'static,
+    M: StreamingMatcher<T> + Send + Sync,
+{
+    fn description(&self) -> String {
+        format!("not ({})", self.0.description())
+    }
+
+    fn checks_messages(&self) -> bool {
+        self.0.checks_messages()
+    }
+
+    fn checks_trailers(&self) -> bool {
+        self.0.checks_trailers()
+    }
+
+    fn metadata_matches(&self, metadata: &MetadataMap, is_trailer: bool) -> bool {
+        self.0.checks_messages() || !self.0.metadata_matches(metadata, is_trailer)
+    }
+
+    fn request_metadata_matches(
+        &self,
+        header: &MetadataMap,
+        trailers: Option<&MetadataMap>,
+    ) -> bool {
+        self.0.checks_messages() || !self.0.request_metadata_matches(header, trailers)
+    }
+
+    async fn stream_match(&self, rx: broadcast::Receiver<Option<Result<T, Status>>>) -> bool {
+        !self.0.checks_messages() || !self.0.stream_match(rx).await
+    }
+
+    async fn request_matches(
+        &self,
+        header: &MetadataMap,
+        trailers: Option<&MetadataMap>,
+        stream: &ReceivedStream<T>,
+    ) -> bool
+    where
+        T: Sync,
+    {
+        !self.0.request_matches(header, trailers, stream).await
+    }
+}
+
+/// Matches when the number of messages in the stream is in the range.
+pub struct MessageCount(Times);
+
+impl MessageCount {
+    pub fn new(times: impl Into<Times>) -> Self {
+        Self(times.into())
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: Clone + Send + 'static> StreamingMatcher<T> for MessageCount {
+    fn description(&self) -> String {
+        format!("message count {}", self.0)
+    }
+
+    async fn stream_match(&self, rx: broadcast::Receiver<Option<Result<T, Status>>>) -> bool {
+        match receive_all(rx).await {
+            Some(stream) => self.0.contains(stream.messages.len() as u64),
+            None => false,
+        }
+    }
+}
+
+/// Matches when the stream is exactly the given messages in the same order.
+pub struct SequenceEq<T>(pub Vec<T>);
+
+#[async_trait::async_trait]
+impl<T> StreamingMatcher<T> for SequenceEq<T>
+where
+    T: PartialEq + Debug + Clone + Send + Sync + 'static,
+{
+    fn description(&self) -> String {
+        format!("messages equal {:?}", self.0)
+    }
+
+    async fn stream_match(&self, rx: broadcast::Receiver<Option<Result<T, Status>>>) -> bool {
+        match receive_all(rx).await {
+            Some(stream) => stream.messages == self.0,
+            None => false,
+        }
+    }
+}
+
+/// Matches when any message in the stream passes the predicate, see [`contains`].
+pub struct Contains<F>(F);
+
+/// Match streams with a message passing the predicate.
+pub fn contains<T, F>(predicate: F) -> Contains<F>
+where
+    F: Fn(&T) -> bool,
+{
+    Contains(predicate)
+}
+
+#[async_trait::async_trait]
+impl<T, F> StreamingMatcher<T> for Contains<F>
+where
+    T: Clone + Send + 'static,
+    F: Fn(&T) -> bool + Send + Sync,
+{
+    fn description(&self) -> String {
+        "contains a message matching predicate".to_string()
+    }
+
+    async fn stream_match(&self, rx: broadcast::Receiver<Option<Result<T, Status>>>) -> bool {
+        match receive_all(rx).await {
+            Some(stream) => stream.messages.iter().any(&self.0),
+            None => false,
+        }
+    }
+}
+
+/// Matches when the messages are in order of the key, see [`ordered_by`].
+pub struct OrderedBy<F>(F);
+
+/// Match streams where the key of each message is greater than or equal to the last one, i.e.
+/// `ordered_by(|x: &Event| x.timestamp)`.
+pub fn ordered_by<T, F, K>(key: F) -> OrderedBy<F>
+where
+    F: Fn(&T) -> K,
+    K: PartialOrd,
+{
+    OrderedBy(key)
+}
+
+#[async_trait::async_trait]
+impl<T, F, K> StreamingMatcher<T> for OrderedBy<F>
+where
+    T: Clone + Send + 'static,
+    F: Fn(&T) -> K + Send + Sync,
+    K: PartialOrd,
+{
+    fn description(&self) -> String {
+        "messages are ordered by key".to_string()
+    }
+
+    async fn stream_match(&self, rx: broadcast::Receiver<Option<Result<T, Status>>>) -> bool {
+        match receive_all(rx).await {
+            Some(stream) => stream
+                .messages
+                .windows(2)
+                .all(|x| (self.0)(&x[0]) <= (self.0)(&x[1])),
+            None => false,
+        }
+    }
+}
+
+/// Matches when the first message passes the predicate, see [`first_message`].
+pub struct FirstMessage<F>(F);
+
+/// Match streams where the first message passes the predicate, empty streams don't match.
+pub fn first_message<T, F>(predicate: F) -> FirstMessage<F>
+where
+    F: Fn(&T) -> bool,
+{
+    FirstMessage(predicate)
+}
+
+#[async_trait::async_trait]
+impl<T, F> StreamingMatcher<T> for FirstMessage<F>
+where
+    T: Clone + Send + 'static,
+    F: Fn(&T) -> bool + Send + Sync,
+{
+    fn description(&self) -> String {
+        "first message matches predicate".to_string()
+    }
+
+    async fn stream_match(&self, rx: broadcast::Receiver<Option<Result<T, Status>>>) -> bool {
+        match receive_all(rx).await {
+            Some(stream) => stream.messages.first().map(&self.0).unwrap_or(false),
+            None => false,
+        }
+    }
+}
+
+/// Matches when the last message passes the predicate, see [`last_message`].
+pub struct LastMessage<F>(F);
+
+/// Match streams where the last message passes the predicate, empty streams don't match.
+pub fn last_message<T, F>(predicate: F) -> LastMessage<F>
+where
+    F: Fn(&T) -> bool,
+{
+    LastMessage(predicate)
+}
+
+#[async_trait::async_trait]
+impl<T, F> StreamingMatcher<T> for LastMessage<F>
+where
+    T: Clone + Send + 'static,
+    F: Fn(&T) -> bool + Send + Sync,
+{
+    fn description(&self) -> String {
+        "last message matches predicate".to_string()
+    }
+
+    async fn stream_match(&self, rx: broadcast::Receiver<Option<Result<T, Status>>>) -> bool {
+        match receive_all(rx).await {
+            Some(stream) => stream.messages.last().map(&self.0).unwrap_or(false),
+            None => false,
+        }
+    }
+}
+
+/// Matches how the client ended the stream, either by half-closing it or with an error such as
+/// a message which can't be decoded.
+pub struct StreamEnd {
+    ending: Ending,
+}
+
+enum Ending {
+    Clean,
+    Errored(Option<Code>),
+}
+
+impl StreamEnd {
+    /// The client half-closed the stream after sending all its messages.
+    pub fn clean() -> Self {
+        Self {
+            ending: Ending::Clean,
+        }
+    }
+
+    /// The stream ended with an error before the client half-closed it.
+    pub fn errored() -> Self {
+        Self {
+            ending: Ending::Errored(None),
+        }
+    }
+
+    /// The stream ended with an error with this code, i.e. `Code::Internal` when the client sends
+    /// a message which can't be decoded or `Code::Cancelled` when the client cancels the call.
+    pub fn errored_with(code: Code) -> Self {
+        Self {
+            ending: Ending::Errored(Some(code)),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: Clone + Send + 'static> StreamingMatcher<T> for StreamEnd {
+    fn description(&self) -> String {
+        match self.ending {
+            Ending::Clean => "stream was half-closed cleanly".to_string(),
+            Ending::Errored(None) => "stream ended with an error".to_string(),
+            Ending::Errored(Some(code)) => format!("stream ended with a {:?} error", code),
+        }
+    }
+
+    async fn stream_match(&self, rx: broadcast::Receiver<Option<Result<T, Status>>>) -> bool {
+        let Some(stream) = receive_all(rx).await else {
+            return false;
+        };
+        match (&self.ending, stream.error) {
+            (Ending::Clean, None) => true,
+            (Ending::Errored(None), Some(_)) => true,
+            (Ending::Errored(Some(code)), Some(status)) => status.code() == *code,
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,130 @@
+//! Records the requests the mocks receive so tests can inspect what the client sent after the
+//! fact, like wiremock's `received_requests`:
+//!
+//! ```
+//! # use tonic_mock::prelude::*;
+//! # mod routeguide {
+//! #     tonic::include_proto!("routeguide");
+//! # }
+//! # use routeguide::route_guide_client::RouteGuideClient;
+//! # use routeguide::route_guide_server::MockRouteGuide;
+//! # use routeguide::{Feature, Point};
+//! # #[tokio::main]
+//! # async fn main() {
+//! # let mut mock = MockRouteGuide::build();
+//! # mock.mock_get_feature().response(FixedResponse::ok(Feature::default()));
+//! # let server = mock.build();
+//! # server.serve().await;
+//! # let addr = server.listening_address().await.unwrap();
+//! # let mut client = RouteGuideClient::connect(addr).await.unwrap();
+//! let point = Point { latitude: 28, longitude: 87 };
+//! client.get_feature(point.clone()).await.unwrap();
+//!
+//! let calls = server.received_requests().await;
+//! assert_eq!(calls[0].method, "get_feature");
+//! assert_eq!(calls[0].request.messages, [format!("{:?}", point)]);
+//! # }
+//! ```
+use crate::deadline::grpc_timeout;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tonic::metadata::MetadataMap;
+use tonic::{Request, Status};
+
+/// A request received by a method mock.
+#[derive(Clone, Debug)]
+pub struct ReceivedRequest<T> {
+    pub metadata: MetadataMap,
+    /// The messages the client sent, for unary and server streaming requests this is the one
+    /// request message.
+    pub messages: Vec<T>,
+    /// The trailing metadata for client streams, if the client sent any
+    pub trailers: Option<MetadataMap>,
+    /// The error a client stream ended with, `None` if the client half-closed it
+    pub error: Option<Status>,
+    pub remote_addr: Option<SocketAddr>,
+    /// When the call was received, streams are recorded once the client stops sending but this is
+    /// still the start of the call
+    pub received_at: SystemTime,
+}
+
+impl<T> ReceivedRequest<T> {
+    /// Record a request where the client sends one message.
+    pub(crate) fn unary(request: &Request<T>) -> Self
+    where
+        T: Clone,
+    {
+        Self {
+            metadata: request.metadata().clone(),
+            messages: vec![request.get_ref().clone()],
+            trailers: None,
+            error: None,
+            remote_addr: request.remote_addr(),
+            received_at: SystemTime::now(),
+        }
+    }
+
+    /// The request message for unary and server streaming requests.
+    pub fn message(&self) -> Option<&T> {
+        self.messages.first()
+    }
+
+    /// The timeout the client set with `grpc-timeout`, if any.
+    pub fn timeout(&self) -> Option<Duration> {
+        grpc_timeout(&self.metadata)
+    }
+
+    /// Convert the messages, i.e. to render them so requests to different methods can be listed
+    /// together.
+    pub fn map<V>(self, f: impl FnMut(T) -> V) -> ReceivedRequest<V> {
+        ReceivedRequest {
+            metadata: self.metadata,
+            messages: self.messages.into_iter().map(f).collect(),
+            trailers: self.trailers,
+            error: self.error,
+            remote_addr: self.remote_addr,
+            received_at: self.received_at,
+        }
+    }
+}
+
+/// A request received by a mock service, the messages are rendered with `Debug` so requests to
+/// all the methods can be listed together.
+#[derive(Clone, Debug)]
+pub struct ReceivedCall {
+    pub method: String,
+    pub request: ReceivedRequest<String>,
+}
+
+/// Shared list of the requests a method mock receives.
+pub(crate) struct RequestLog<T>(Arc<Mutex<Vec<ReceivedRequest<T>>>>);
+
+impl<T> Default for RequestLog<T> {
+    fn default() -> Self {
+        Self(Default::default())
+    }
+}
+
+impl<T> Clone for RequestLog<T> {
+    fn clone(&self) -> Self {
+        Self(Arc::clone(&self.0))
+    }
+}
+
+impl<T> RequestLog<T> {
+    pub(crate) fn record(&self, request: ReceivedRequest<T>) {
+        self.0.lock().unwrap().push(request);
+    }
+
+    pub(crate) fn get(&self) -> Vec<ReceivedRequest<T>>
+    where
+        T: Clone,
+    {
+        self.0.lock().unwrap().clone()
+    }
+
+    pub(crate) fn clear(&self) {
+        self.0.lock().unwrap().clear();
+    }
+}
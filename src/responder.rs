@@ -1,37 +1,968 @@
+use crate::fault::{self, Fault};
+use crate::server::ResponseTrailers;
 use crate::*;
-use tonic::{Response, Status};
+use bytes::Bytes;
+use futures::future;
+use futures::stream::{self, StreamExt};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tonic::metadata::{MetadataKey, MetadataValue};
+use tonic::{Code, Response, Status};
 
+/// Responds with the same message or status every time. Response headers and trailers can be
+/// added for clients which read them, i.e. a request id or rate limits:
+///
+/// ```
+/// # use tonic_mock::responder::FixedResponse;
+/// let response = FixedResponse::ok(1)
+///     .with_header("x-request-id", "abc123")
+///     .with_trailer("x-ratelimit-remaining", "0");
+/// ```
+///
+/// An error status is sent as trailers only, so for errors the headers and trailers are both
+/// added to the status metadata.
 pub struct FixedResponse<U> {
     response: Result<U, Status>,
+    headers: MetadataMap,
+    trailers: MetadataMap,
+    details: Option<Bytes>,
 }
 
 impl<U> FixedResponse<U> {
     pub fn ok(u: U) -> Self {
-        Self { response: Ok(u) }
+        Self::new(Ok(u))
     }
 
     pub fn err(s: Status) -> Self {
-        Self { response: Err(s) }
+        Self::new(Err(s))
     }
+
+    fn new(response: Result<U, Status>) -> Self {
+        Self {
+            response,
+            headers: MetadataMap::new(),
+            trailers: MetadataMap::new(),
+            details: None,
+        }
+    }
+
+    /// Add ASCII metadata to the response headers.
+    ///
+    /// # Panics
+    ///
+    /// If `key` isn't a valid ASCII metadata key, i.e. it has spaces in it or ends in `-bin`, or
+    /// `value` has characters which aren't allowed in a header value.
+    pub fn with_header(mut self, key: &str, value: &str) -> Self {
+        insert_metadata(&mut self.headers, key, value);
+        self
+    }
+
+    /// Add ASCII metadata to the response trailers.
+    ///
+    /// Tonic has no way to add trailers to a successful unary response, so the mock server's
+    /// router sends them from the response extensions. Served by a plain tonic `Server` they're
+    /// dropped, error statuses keep them either way.
+    ///
+    /// # Panics
+    ///
+    /// Like [`with_header`](Self::with_header), if `key` or `value` aren't valid ASCII metadata.
+    pub fn with_trailer(mut self, key: &str, value: &str) -> Self {
+        insert_metadata(&mut self.trailers, key, value);
+        self
+    }
+
+    /// Set the details of an error status, i.e. an encoded `google.rpc.Status`. This replaces any
+    /// details the status already has and does nothing for successful responses.
+    pub fn with_status_details(mut self, details: impl Into<Bytes>) -> Self {
+        self.details = Some(details.into());
+        self
+    }
+
+    fn to_response(&self) -> Result<Response<U>, Status>
+    where
+        U: Clone,
+    {
+        match &self.response {
+            Ok(u) => {
+                let mut response = Response::new(u.clone());
+                *response.metadata_mut() = self.headers.clone();
+                if !self.trailers.is_empty() {
+                    let trailers = ResponseTrailers(self.trailers.clone());
+                    response.extensions_mut().insert(trailers);
+                }
+                Ok(response)
+            }
+            Err(status) => {
+                let status = with_trailers(status, &self.headers);
+                let status = with_trailers(&status, &self.trailers);
+                let details = self.details.clone();
+                Err(Status::with_details_and_metadata(
+                    status.code(),
+                    status.message(),
+                    details.unwrap_or_else(|| status.details().to_vec().into()),
+                    status.metadata().clone(),
+                ))
+            }
+        }
+    }
+}
+
+fn insert_metadata(metadata: &mut MetadataMap, key: &str, value: &str) {
+    let key = MetadataKey::from_bytes(key.as_bytes()).expect("Invalid metadata key");
+    let value = MetadataValue::try_from(value).expect("Invalid metadata value");
+    metadata.insert(key, value);
 }
 
 impl<U: Default> FixedResponse<U> {
     pub fn default_ok() -> Self {
+        Self::ok(Default::default())
+    }
+}
+
+impl<T, U> Responder<T, U> for FixedResponse<U>
+where
+    U: Clone,
+{
+    fn respond(&self, _request: Request<T>) -> Result<Response<U>, Status> {
+        self.to_response()
+    }
+}
+
+impl<T, U> StreamingResponder<T, U> for FixedResponse<U>
+where
+    U: Clone,
+{
+    fn response(
+        &self,
+        _header: &MetadataMap,
+        _messages: &[T],
+        _trailers: Option<&MetadataMap>,
+        _error: Option<&Status>,
+    ) -> Result<Response<U>, Status> {
+        self.to_response()
+    }
+}
+
+/// What a [`SequenceResponder`] does once every response has been sent.
+#[derive(Clone, Debug)]
+pub enum Exhausted {
+    /// Keep sending the last response
+    RepeatLast,
+    /// Start again from the first response
+    Cycle,
+    /// Fail every call after that with the status
+    Fail(Status),
+}
+
+/// Sends a different response for each call, in the order they were added. This is for testing
+/// retries, i.e. fail twice with `UNAVAILABLE` then succeed:
+///
+/// ```
+/// # use tonic::Status;
+/// # use tonic_mock::responder::SequenceResponder;
+/// let responses = SequenceResponder::new()
+///     .err(Status::unavailable("try again"))
+///     .err(Status::unavailable("try again"))
+///     .ok("done".to_string());
+/// ```
+///
+/// Once the responses run out the last one is repeated, change this with `when_exhausted`.
+pub struct SequenceResponder<U> {
+    responses: Vec<Result<U, Status>>,
+    exhausted: Exhausted,
+    calls: AtomicUsize,
+}
+
+impl<U> Default for SequenceResponder<U> {
+    fn default() -> Self {
         Self {
-            response: Ok(Default::default()),
+            responses: vec![],
+            exhausted: Exhausted::RepeatLast,
+            calls: AtomicUsize::new(0),
         }
     }
 }
 
-impl<T, U> Responder<T, U> for FixedResponse<U>
+impl<U> SequenceResponder<U> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Respond with the message on the next call.
+    pub fn ok(mut self, u: U) -> Self {
+        self.responses.push(Ok(u));
+        self
+    }
+
+    /// Fail the next call with the status.
+    pub fn err(mut self, status: Status) -> Self {
+        self.responses.push(Err(status));
+        self
+    }
+
+    pub fn when_exhausted(mut self, exhausted: Exhausted) -> Self {
+        self.exhausted = exhausted;
+        self
+    }
+
+    fn next(&self) -> Result<U, Status>
+    where
+        U: Clone,
+    {
+        let call = self.calls.fetch_add(1, Ordering::SeqCst);
+        let response = match (self.responses.get(call), &self.exhausted) {
+            (Some(response), _) => Some(response),
+            (None, Exhausted::RepeatLast) => self.responses.last(),
+            (None, Exhausted::Cycle) if !self.responses.is_empty() => {
+                self.responses.get(call % self.responses.len())
+            }
+            (None, Exhausted::Fail(status)) => return Err(status.clone()),
+            (None, Exhausted::Cycle) => None,
+        };
+        response
+            .cloned()
+            .unwrap_or_else(|| Err(Status::internal("The response sequence is empty")))
+    }
+}
+
+impl<T, U> Responder<T, U> for SequenceResponder<U>
 where
     U: Clone,
 {
-    fn respond(&self, request: Request<T>) -> Result<Response<U>, Status> {
-        self.response.clone().map(|x| Response::new(x))
+    fn respond(&self, _request: Request<T>) -> Result<Response<U>, Status> {
+        self.next().map(Response::new)
+    }
+}
+
+impl<T, U> StreamingResponder<T, U> for SequenceResponder<U>
+where
+    U: Clone,
+{
+    fn response(
+        &self,
+        _header: &MetadataMap,
+        _messages: &[T],
+        _trailers: Option<&MetadataMap>,
+        _error: Option<&Status>,
+    ) -> Result<Response<U>, Status> {
+        self.next().map(Response::new)
+    }
+}
+
+/// A scripted response for server streaming methods. Each message can be sent after a delay and
+/// the stream can be ended with a status and trailing metadata.
+///
+/// gRPC ends the stream on the first error, so any items after an `Err` are never sent.
+pub struct StreamResponse<U> {
+    /// The items with their delays, `None` for messages which wait for the `item_delay`
+    items: Vec<(Option<Duration>, Result<U, Status>)>,
+    item_delay: Duration,
+    trailers: Option<MetadataMap>,
+}
+
+impl<U> Default for StreamResponse<U> {
+    fn default() -> Self {
+        Self {
+            items: vec![],
+            item_delay: Duration::ZERO,
+            trailers: None,
+        }
+    }
+}
+
+impl<U> StreamResponse<U> {
+    pub fn new(items: Vec<Result<U, Status>>) -> Self {
+        Self {
+            items: items.into_iter().map(|x| (None, x)).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Send a message after the previous one.
+    pub fn message(mut self, u: U) -> Self {
+        self.items.push((None, Ok(u)));
+        self
+    }
+
+    /// Wait for `delay` before sending the message, this overrides the `item_delay`.
+    pub fn delayed_message(mut self, u: U, delay: Duration) -> Self {
+        self.items.push((Some(delay), Ok(u)));
+        self
+    }
+
+    /// Wait for `delay` before sending each message which doesn't have a delay of its own, this
+    /// applies whether the messages are added before or after. The status ending the stream is
+    /// sent straight after the last message.
+    pub fn item_delay(mut self, delay: Duration) -> Self {
+        self.item_delay = delay;
+        self
+    }
+
+    /// End the stream with the given status after the messages.
+    pub fn status(mut self, status: Status) -> Self {
+        self.items.push((None, Err(status)));
+        self
+    }
+
+    /// Trailing metadata to send when the stream ends. If the stream ends with an error status
+    /// this is added to the status metadata.
+    pub fn trailers(mut self, trailers: MetadataMap) -> Self {
+        self.trailers = Some(trailers);
+        self
+    }
+}
+
+impl<U> StreamResponse<U>
+where
+    U: Clone + Send + 'static,
+{
+    fn items(&self) -> Vec<(Duration, Result<U, Status>)> {
+        let mut items = vec![];
+        for (delay, item) in &self.items {
+            match item {
+                Ok(u) => items.push((delay.unwrap_or(self.item_delay), Ok(u.clone()))),
+                Err(status) => {
+                    let status = match self.trailers.as_ref() {
+                        Some(trailers) => with_trailers(status, trailers),
+                        None => status.clone(),
+                    };
+                    items.push((Duration::ZERO, Err(status)));
+                    return items;
+                }
+            }
+        }
+        // Tonic sends the metadata of an error status as trailers, so an OK status lets us send
+        // trailers without failing the stream.
+        if let Some(trailers) = self.trailers.clone() {
+            items.push((
+                Duration::ZERO,
+                Err(Status::with_metadata(Code::Ok, "", trailers)),
+            ));
+        }
+        items
+    }
+}
+
+fn with_trailers(status: &Status, trailers: &MetadataMap) -> Status {
+    let mut metadata = status.metadata().clone().into_headers();
+    metadata.extend(trailers.clone().into_headers());
+    Status::with_details_and_metadata(
+        status.code(),
+        status.message(),
+        status.details().to_vec().into(),
+        MetadataMap::from_headers(metadata),
+    )
+}
+
+impl<T, U> ServerStreamResponder<T, U> for StreamResponse<U>
+where
+    U: Clone + Send + 'static,
+{
+    fn respond(&self, _request: Request<T>) -> Result<Response<ResponseStream<U>>, Status> {
+        let stream = stream::iter(self.items()).then(|(delay, item)| async move {
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+            item
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+/// How long a [`Delayed`] responder waits.
+#[derive(Clone, Debug)]
+pub enum Delay {
+    Fixed(Duration),
+    /// A uniformly random delay between `min` and `max`, seeded so test runs are repeatable
+    Uniform {
+        min: Duration,
+        max: Duration,
+        seed: u64,
+    },
+    /// A delay for each call in turn, the last one is repeated once they run out
+    Sequence(Vec<Duration>),
+}
+
+/// Hands out the delays, shared with the response streams for per-message delays.
+struct Delays {
+    delay: Delay,
+    rng: Mutex<StdRng>,
+    count: AtomicUsize,
+}
+
+impl Delays {
+    fn next(&self) -> Duration {
+        match &self.delay {
+            Delay::Fixed(delay) => *delay,
+            Delay::Uniform { min, max, .. } if max > min => {
+                self.rng.lock().unwrap().gen_range(*min..=*max)
+            }
+            Delay::Uniform { min, .. } => *min,
+            Delay::Sequence(delays) => {
+                let n = self.count.fetch_add(1, Ordering::SeqCst);
+                delays.get(n).or(delays.last()).copied().unwrap_or_default()
+            }
+        }
+    }
+
+    /// Delay the items in a response stream, either just the first or every one.
+    fn delay_stream<U: Send + 'static>(
+        self: Arc<Self>,
+        stream: ResponseStream<U>,
+        per_message: bool,
+    ) -> ResponseStream<U> {
+        let mut first = true;
+        Box::pin(stream.then(move |item| {
+            let delay = (per_message || first).then(|| self.next());
+            first = false;
+            async move {
+                if let Some(delay) = delay {
+                    tokio::time::sleep(delay).await;
+                }
+                item
+            }
+        }))
+    }
+
+    /// Delay a streamed response, an error is sent as the end of the stream after the delay.
+    fn delay_response<U: Send + 'static>(
+        self: Arc<Self>,
+        response: Result<Response<ResponseStream<U>>, Status>,
+        per_message: bool,
+    ) -> Result<Response<ResponseStream<U>>, Status> {
+        let response = response.unwrap_or_else(|status| {
+            Response::new(Box::pin(stream::once(future::ready(Err(status)))))
+        });
+        let (metadata, stream, extensions) = response.into_parts();
+        Ok(Response::from_parts(
+            metadata,
+            self.delay_stream(stream, per_message),
+            extensions,
+        ))
+    }
+}
+
+/// Wraps a responder to make it slow, i.e. to test client deadlines and timeouts:
+///
+/// ```
+/// # use std::time::Duration;
+/// # use tonic_mock::responder::{Delayed, FixedResponse};
+/// let slow = Delayed::fixed(FixedResponse::ok(1), Duration::from_millis(100));
+/// ```
+///
+/// For unary and client streaming methods this can wrap any responder the mock takes, sync or
+/// async. For streamed responses the response headers are sent straight away and the first message is
+/// delayed, or every message with `per_message`.
+pub struct Delayed<R> {
+    inner: R,
+    delays: Arc<Delays>,
+    per_message: bool,
+}
+
+impl<R> Delayed<R> {
+    pub fn new(inner: R, delay: Delay) -> Self {
+        let seed = match &delay {
+            Delay::Uniform { seed, .. } => *seed,
+            _ => 0,
+        };
+        Self {
+            inner,
+            delays: Arc::new(Delays {
+                delay,
+                rng: Mutex::new(StdRng::seed_from_u64(seed)),
+                count: AtomicUsize::new(0),
+            }),
+            per_message: false,
+        }
+    }
+
+    pub fn fixed(inner: R, delay: Duration) -> Self {
+        Self::new(inner, Delay::Fixed(delay))
+    }
+
+    pub fn uniform(inner: R, min: Duration, max: Duration, seed: u64) -> Self {
+        Self::new(inner, Delay::Uniform { min, max, seed })
+    }
+
+    pub fn sequence(inner: R, delays: Vec<Duration>) -> Self {
+        Self::new(inner, Delay::Sequence(delays))
+    }
+
+    /// For streamed responses wait before every message instead of only the first.
+    pub fn per_message(mut self) -> Self {
+        self.per_message = true;
+        self
+    }
+}
+
+impl<R, M, T, U> IntoAsyncResponder<T, U, marker::Delayed<M>> for Delayed<R>
+where
+    T: Send + 'static,
+    U: 'static,
+    R: IntoAsyncResponder<T, U, M>,
+{
+    fn into_boxed(self) -> BoxResponder<T, U> {
+        let inner = self.inner.into_boxed();
+        let delays = self.delays;
+        Arc::new(move |request| {
+            let inner = Arc::clone(&inner);
+            let delay = delays.next();
+            Box::pin(async move {
+                tokio::time::sleep(delay).await;
+                inner(request).await
+            })
+        })
+    }
+}
+
+impl<R, M, T, U> IntoStreamingResponder<T, U, marker::Delayed<M>> for Delayed<R>
+where
+    T: Sync + 'static,
+    U: 'static,
+    R: IntoStreamingResponder<T, U, M>,
+{
+    fn into_boxed(self) -> BoxStreamingResponder<T, U> {
+        let inner = self.inner.into_boxed();
+        let delays = self.delays;
+        box_streaming(move |header, messages, trailers, error| {
+            let inner = Arc::clone(&inner);
+            let delay = delays.next();
+            Box::pin(async move {
+                tokio::time::sleep(delay).await;
+                inner(header, messages, trailers, error).await
+            })
+        })
+    }
+}
+
+impl<R, T, U> ServerStreamResponder<T, U> for Delayed<R>
+where
+    R: ServerStreamResponder<T, U>,
+    U: Send + 'static,
+{
+    fn respond(&self, request: Request<T>) -> Result<Response<ResponseStream<U>>, Status> {
+        let response = self.inner.respond(request);
+        Arc::clone(&self.delays).delay_response(response, self.per_message)
+    }
+}
+
+impl<R, T, U> BidirStreamResponder<T, U> for Delayed<R>
+where
+    R: BidirStreamResponder<T, U>,
+    U: Send + 'static,
+{
+    fn respond(
+        &self,
+        header: &MetadataMap,
+        messages: RequestStream<T>,
+    ) -> Result<Response<ResponseStream<U>>, Status> {
+        let response = self.inner.respond(header, messages);
+        Arc::clone(&self.delays).delay_response(response, self.per_message)
+    }
+}
+
+/// Makes a responder flaky for soak tests, on each call it can be slowed down, fail with a status
+/// or have a fault injected, each with its own probability. The random numbers are seeded so a
+/// failing run can be repeated:
+///
+/// ```
+/// # use std::time::Duration;
+/// # use tonic::Status;
+/// # use tonic_mock::fault::{Fault, Reason};
+/// # use tonic_mock::responder::{Chaos, FixedResponse};
+/// let flaky = Chaos::new(FixedResponse::ok(1), 42)
+///     .latency(0.2, Duration::from_millis(50))
+///     .error(0.1, Status::unavailable("try again"))
+///     .fault(0.01, Fault::Reset(Reason::INTERNAL_ERROR));
+/// ```
+pub struct Chaos<R> {
+    inner: R,
+    rng: Mutex<StdRng>,
+    latency: Option<(f64, Duration)>,
+    error: Option<(f64, Status)>,
+    fault: Option<(f64, Fault)>,
+}
+
+/// What a [`Chaos`] responder does with a call after any latency.
+enum Outcome {
+    Respond,
+    Fail(Status),
+    Inject(Fault),
+}
+
+impl<R> Chaos<R> {
+    pub fn new(inner: R, seed: u64) -> Self {
+        Self {
+            inner,
+            rng: Mutex::new(StdRng::seed_from_u64(seed)),
+            latency: None,
+            error: None,
+            fault: None,
+        }
+    }
+
+    /// Wait for `delay` before responding on a `probability` of the calls.
+    pub fn latency(mut self, probability: f64, delay: Duration) -> Self {
+        self.latency = Some((check_probability(probability), delay));
+        self
+    }
+
+    /// Fail a `probability` of the calls with the status.
+    pub fn error(mut self, probability: f64, status: Status) -> Self {
+        self.error = Some((check_probability(probability), status));
+        self
+    }
+
+    /// Inject the fault on a `probability` of the calls, this is checked before `error`.
+    pub fn fault(mut self, probability: f64, fault: Fault) -> Self {
+        self.fault = Some((check_probability(probability), fault));
+        self
+    }
+
+    /// Decide what happens to a call. Every call takes the same number of random numbers
+    /// whatever happens, so the calls after a failure are unaffected by it.
+    fn roll(&self) -> (Option<Duration>, Outcome) {
+        let mut rng = self.rng.lock().unwrap();
+        let mut chance = |probability: Option<f64>| rng.gen::<f64>() < probability.unwrap_or(0.0);
+        let slow = chance(self.latency.as_ref().map(|x| x.0));
+        let inject = chance(self.fault.as_ref().map(|x| x.0));
+        let fail = chance(self.error.as_ref().map(|x| x.0));
+        let latency = self.latency.as_ref().filter(|_| slow).map(|x| x.1);
+        let outcome = match (&self.fault, &self.error) {
+            (Some((_, fault)), _) if inject => Outcome::Inject(*fault),
+            (_, Some((_, status))) if fail => Outcome::Fail(status.clone()),
+            _ => Outcome::Respond,
+        };
+        (latency, outcome)
+    }
+}
+
+fn check_probability(probability: f64) -> f64 {
+    assert!(
+        (0.0..=1.0).contains(&probability),
+        "{} isn't a probability, it has to be between 0 and 1",
+        probability
+    );
+    probability
+}
+
+impl<R, M, T, U> IntoAsyncResponder<T, U, marker::Chaos<M>> for Chaos<R>
+where
+    T: Send + 'static,
+    U: 'static,
+    R: IntoAsyncResponder<T, U, M>,
+{
+    fn into_boxed(self) -> BoxResponder<T, U> {
+        let Chaos {
+            inner,
+            rng,
+            latency,
+            error,
+            fault,
+        } = self;
+        let inner = inner.into_boxed();
+        // Only the dice are needed from here on
+        let chaos = Arc::new(Chaos {
+            inner: (),
+            rng,
+            latency,
+            error,
+            fault,
+        });
+        Arc::new(move |request| {
+            let inner = Arc::clone(&inner);
+            let (latency, outcome) = chaos.roll();
+            Box::pin(async move {
+                if let Some(delay) = latency {
+                    tokio::time::sleep(delay).await;
+                }
+                match outcome {
+                    Outcome::Respond => inner(request).await,
+                    Outcome::Fail(status) => Err(status),
+                    Outcome::Inject(fault) => Err(fault::inject(fault)),
+                }
+            })
+        })
+    }
+}
+
+/// What makes a rule in a [`Conversation`] send its response.
+enum Trigger<T> {
+    /// An inbound message matches the predicate
+    Matches(Arc<dyn Fn(&T) -> bool + Send + Sync>),
+    /// The given number of inbound messages have been received
+    After(usize),
+}
+
+impl<T> Clone for Trigger<T> {
+    fn clone(&self) -> Self {
+        match self {
+            Self::Matches(f) => Self::Matches(Arc::clone(f)),
+            Self::After(n) => Self::After(*n),
+        }
+    }
+}
+
+impl<T> Trigger<T> {
+    fn fires(&self, message: &T, received: usize) -> bool {
+        match self {
+            Self::Matches(f) => f(message),
+            Self::After(n) => *n == received,
+        }
+    }
+}
+
+/// A scripted conversation for bidirectional streaming methods. Each inbound message is checked
+/// against the rules in the order they were added and every rule that fires sends its response.
+///
+/// ```
+/// # use tonic::Status;
+/// # use tonic_mock::responder::Conversation;
+/// let conversation = Conversation::<String, String>::new()
+///     .when(|msg| msg == "ping", "pong".to_string())
+///     .after(3, "that's enough".to_string())
+///     .on_close(Status::aborted("bye"));
+/// ```
+pub struct Conversation<T, U> {
+    rules: Vec<(Trigger<T>, U)>,
+    on_close: Option<Status>,
+}
+
+impl<T, U> Default for Conversation<T, U> {
+    fn default() -> Self {
+        Self {
+            rules: vec![],
+            on_close: None,
+        }
+    }
+}
+
+impl<T, U> Conversation<T, U> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// When a message matching the predicate arrives send `response`.
+    pub fn when(
+        mut self,
+        predicate: impl Fn(&T) -> bool + Send + Sync + 'static,
+        response: U,
+    ) -> Self {
+        self.rules
+            .push((Trigger::Matches(Arc::new(predicate)), response));
+        self
+    }
+
+    /// After `n` inbound messages have been received send `response`.
+    pub fn after(mut self, n: usize, response: U) -> Self {
+        self.rules.push((Trigger::After(n), response));
+        self
+    }
+
+    /// End the stream with the given status when the client half-closes. Otherwise the stream is
+    /// closed with an OK status.
+    pub fn on_close(mut self, status: Status) -> Self {
+        self.on_close = Some(status);
+        self
+    }
+}
+
+impl<T, U> BidirStreamResponder<T, U> for Conversation<T, U>
+where
+    T: Send + 'static,
+    U: Clone + Send + 'static,
+{
+    fn respond(
+        &self,
+        _header: &MetadataMap,
+        messages: RequestStream<T>,
+    ) -> Result<Response<ResponseStream<U>>, Status> {
+        let rules = self.rules.clone();
+        let mut received = 0;
+        // The stream only ends with an error if the client has gone or can't be understood,
+        // so the conversation just stops
+        let messages = messages.take_while(|x| future::ready(x.is_ok()));
+        let replies = messages.filter_map(|x| future::ready(x.ok()));
+        let replies = replies.flat_map(move |message| {
+            received += 1;
+            let replies = rules
+                .iter()
+                .filter(|(trigger, _)| trigger.fires(&message, received))
+                .map(|(_, response)| Ok(response.clone()))
+                .collect::<Vec<_>>();
+            stream::iter(replies)
+        });
+        let close = stream::iter(self.on_close.clone().map(Err));
+        Ok(Response::new(Box::pin(replies.chain(close))))
+    }
+}
+
+/// Injects a network fault instead of responding, to test client retries and reconnection
+/// against the failures a real connection has:
+///
+/// ```
+/// # use tonic_mock::fault::{Fault, Reason};
+/// # use tonic_mock::responder::FaultResponse;
+/// // Reset the stream with REFUSED_STREAM
+/// let refused = FaultResponse::<String>::new(Fault::Reset(Reason::REFUSED_STREAM));
+/// // Send two messages then drop the connection
+/// let dropped = FaultResponse::after(vec![1, 2], Fault::CloseConnection);
+/// ```
+///
+/// The messages are only sent for streamed responses. Faults need the mock server, a service
+/// called some other way gets an `UNAVAILABLE` status instead.
+pub struct FaultResponse<U> {
+    messages: Vec<U>,
+    fault: Fault,
+}
+
+impl<U> FaultResponse<U> {
+    pub fn new(fault: Fault) -> Self {
+        Self {
+            messages: vec![],
+            fault,
+        }
+    }
+
+    /// Send the messages before injecting the fault.
+    pub fn after(messages: Vec<U>, fault: Fault) -> Self {
+        Self { messages, fault }
+    }
+
+    fn stream(&self) -> Result<Response<ResponseStream<U>>, Status>
+    where
+        U: Clone + Send + 'static,
+    {
+        if self.messages.is_empty() {
+            return Err(fault::inject(self.fault));
+        }
+        // The mock server injects it once the messages are written
+        if let Some(faults) = fault::current() {
+            faults.inject_at_end(self.fault);
+        }
+        let messages = self.messages.clone().into_iter().map(Ok);
+        let end = Err(fault::injected(self.fault));
+        Ok(Response::new(Box::pin(stream::iter(
+            messages.chain(std::iter::once(end)),
+        ))))
+    }
+}
+
+impl<T, U> Responder<T, U> for FaultResponse<U> {
+    fn respond(&self, _request: Request<T>) -> Result<Response<U>, Status> {
+        Err(fault::inject(self.fault))
+    }
+}
+
+impl<T, U> StreamingResponder<T, U> for FaultResponse<U> {
+    fn response(
+        &self,
+        _header: &MetadataMap,
+        _messages: &[T],
+        _trailers: Option<&MetadataMap>,
+        _error: Option<&Status>,
+    ) -> Result<Response<U>, Status> {
+        Err(fault::inject(self.fault))
+    }
+}
+
+impl<T, U> ServerStreamResponder<T, U> for FaultResponse<U>
+where
+    U: Clone + Send + 'static,
+{
+    fn respond(&self, _request: Request<T>) -> Result<Response<ResponseStream<U>>, Status> {
+        self.stream()
+    }
+}
+
+impl<T, U> BidirStreamResponder<T, U> for FaultResponse<U>
+where
+    U: Clone + Send + 'static,
+{
+    fn respond(
+        &self,
+        _header: &MetadataMap,
+        _messages: RequestStream<T>,
+    ) -> Result<Response<ResponseStream<U>>, Status> {
+        self.stream()
     }
 }
 
 pub struct Unimplemented;
 
 impl<T, U> Responder<T, U> for Unimplemented {}
+
+impl<T, U> ServerStreamResponder<T, U> for Unimplemented {}
+
+impl<T, U> BidirStreamResponder<T, U> for Unimplemented {}
+
+impl<T, U> StreamingResponder<T, U> for Unimplemented {}
+
+/// Responds with the result of a closure, so a response computed from the request doesn't need
+/// its own responder type.
+///
+/// ```
+/// # use tonic::{Request, Response};
+/// # use tonic_mock::responder::ResponseFn;
+/// let echo = ResponseFn::new(|req: &Request<String>| Ok(Response::new(req.get_ref().clone())));
+/// ```
+pub struct ResponseFn<F> {
+    f: F,
+}
+
+impl<F> ResponseFn<F> {
+    pub fn new<T, U>(f: F) -> Self
+    where
+        F: Fn(&Request<T>) -> Result<Response<U>, Status>,
+    {
+        Self { f }
+    }
+}
+
+impl<T, U, F> Responder<T, U> for ResponseFn<F>
+where
+    F: Fn(&Request<T>) -> Result<Response<U>, Status>,
+{
+    fn respond(&self, request: Request<T>) -> Result<Response<U>, Status> {
+        (self.f)(&request)
+    }
+}
+
+/// Responds with the result of an async closure, i.e. to wait on something in the test before
+/// responding. The closure takes the request by value so the future can hold on to it.
+///
+/// ```
+/// # use tonic::{Request, Response};
+/// # use tonic_mock::responder::AsyncResponseFn;
+/// let echo = AsyncResponseFn::new(|req: Request<String>| async move {
+///     Ok(Response::new(req.into_inner()))
+/// });
+/// ```
+pub struct AsyncResponseFn<F> {
+    f: F,
+}
+
+impl<F> AsyncResponseFn<F> {
+    pub fn new<T, U, Fut>(f: F) -> Self
+    where
+        F: Fn(Request<T>) -> Fut,
+        Fut: Future<Output = Result<Response<U>, Status>>,
+    {
+        Self { f }
+    }
+}
+
+impl<T, U, F, Fut> IntoAsyncResponder<T, U, marker::Closure> for AsyncResponseFn<F>
+where
+    F: Fn(Request<T>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Response<U>, Status>> + Send + 'static,
+{
+    fn into_boxed(self) -> BoxResponder<T, U> {
+        let f = self.f;
+        Arc::new(move |request| Box::pin(f(request)))
+    }
+}
@@ -1,11 +1,34 @@
+use crate::fault::{
+    self, BoxError, ConnectionFaults, Fault, FaultBody, FaultyConnection, RequestFaults,
+};
 use async_trait::async_trait;
+use bytes::Bytes;
 use deadpool::managed::{Object, Pool};
+use futures::future::BoxFuture;
+use http_body::Body as _;
+use hyper::server::accept::{self, Accept};
+use hyper::server::conn::AddrIncoming;
+use hyper::service::make_service_fn;
 use once_cell::sync::Lazy;
+use std::collections::HashMap;
 use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
 use tokio::net::TcpListener;
+use tokio::sync::oneshot;
 use tonic::body::BoxBody;
-use tonic::transport::server::TcpIncoming;
+use tonic::metadata::MetadataMap;
+use tonic::server::NamedService;
+use tonic::transport::server::TcpConnectInfo;
 use tonic::transport::Body;
+use tonic::Status;
+use tower::util::{BoxCloneService, ServiceExt};
+use tower_service::Service;
+use tracing::{debug, info};
 
 static MOCK_SERVER_POOL: Lazy<Pool<MockServerPoolManager>> = Lazy::new(|| {
     Pool::builder(MockServerPoolManager)
@@ -23,44 +46,390 @@ async fn get_pooled_mock_server() -> PooledMockServer {
         .expect("Failed to get a GrpcMockServer from the pool")
 }
 
+/// A mock server taken from a pool of running servers, this avoids paying the startup cost for
+/// every test. Register the mock services on it and when it's dropped it's returned to the pool
+/// and the services are removed.
+///
+/// ```no_run
+/// # use tonic_mock::server::MockServer;
+/// # async fn example() {
+/// let server = MockServer::start().await;
+/// // mock.mount(&server);
+/// let uri = server.uri();
+/// # }
+/// ```
 pub struct MockServer {
-    listener: TcpListener,
+    server: PooledMockServer,
 }
 
 impl MockServer {
     pub async fn start() -> Self {
-        let listener = TcpListener::bind("127.0.0.1:0")
-            .await
-            .expect("Failed to bind an OS port for a mock server.");
-        Self { listener }
+        Self {
+            server: get_pooled_mock_server().await,
+        }
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.server.addr
+    }
+
+    /// The URI to connect a client to, i.e. `http://127.0.0.1:4567`
+    pub fn uri(&self) -> String {
+        format!("http://{}", self.server.addr)
+    }
+
+    /// Register a service to be served, replacing any service already registered with the same
+    /// name. Generated mocks can register themselves via `mount`.
+    pub fn register<S>(&self, service: S)
+    where
+        S: Service<http::Request<BoxBody>, Response = http::Response<BoxBody>, Error = Infallible>
+            + NamedService
+            + Clone
+            + Send
+            + 'static,
+        S::Future: Send + 'static,
+    {
+        self.server.router.register(service);
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        // Stop serving the test's services straight away, not only when the server is next
+        // taken from the pool
+        self.server.router.clear();
+    }
+}
+
+type RouteService = BoxCloneService<http::Request<BoxBody>, http::Response<BoxBody>, Infallible>;
+
+/// Routes requests to the registered services by the service name in the path. Requests for
+/// services which aren't registered get an `UNIMPLEMENTED` status.
+///
+/// This is also where the faults the responders inject are applied to the responses and the
+/// connection.
+#[derive(Clone, Default)]
+pub(crate) struct MockRouter {
+    services: Arc<Mutex<HashMap<&'static str, RouteService>>>,
+    connect_info: Option<TcpConnectInfo>,
+    faults: Arc<ConnectionFaults>,
+}
+
+impl MockRouter {
+    fn register<S>(&self, service: S)
+    where
+        S: Service<http::Request<BoxBody>, Response = http::Response<BoxBody>, Error = Infallible>
+            + NamedService
+            + Clone
+            + Send
+            + 'static,
+        S::Future: Send + 'static,
+    {
+        self.services
+            .lock()
+            .unwrap()
+            .insert(S::NAME, BoxCloneService::new(service));
+    }
+
+    fn clear(&self) {
+        self.services.lock().unwrap().clear();
+    }
+
+    /// Router for a single connection, this is so the connection info is available in the
+    /// request like it is with a tonic server.
+    fn for_connection(&self, conn: &FaultyConnection) -> Self {
+        Self {
+            services: Arc::clone(&self.services),
+            connect_info: Some(conn.connect_info()),
+            faults: conn.faults(),
+        }
+    }
+}
+
+/// Apply a fault injected while the service was handling the request. Resetting the stream before
+/// the response headers are sent is done by failing the response.
+async fn apply_fault(
+    response: http::Response<BoxBody>,
+    faults: Arc<RequestFaults>,
+) -> Result<http::Response<FaultBody>, BoxError> {
+    match faults.get() {
+        Some(Fault::Reset(reason)) => Err(h2::Error::from(reason).into()),
+        Some(Fault::Abort) => {
+            let response = http::Response::builder()
+                .header(http::header::CONTENT_TYPE, "application/grpc")
+                .body(tonic::body::empty_body())
+                .unwrap();
+            Ok(response.map(|body| FaultBody::new(body, faults)))
+        }
+        // The connection is going, there's no response to send
+        Some(Fault::CloseConnection) => futures::future::pending().await,
+        Some(Fault::GoAway(_)) | None => {
+            let (mut parts, body) = response.into_parts();
+            let trailers = parts.extensions.remove::<ResponseTrailers>();
+            let body = FaultBody::new(body, faults).with_trailers(trailers.map(|x| x.0));
+            Ok(http::Response::from_parts(parts, body))
+        }
+    }
+}
+
+/// Trailing metadata for a successful response. Tonic only sends trailers of its own for unary
+/// responses, so responders put them in the response extensions and the router adds them.
+#[derive(Clone)]
+pub(crate) struct ResponseTrailers(pub(crate) MetadataMap);
+
+/// Set when the client cancels a request by resetting the stream. Hyper ends the request body
+/// cleanly when that happens, so the router looks out for it and puts this in the request
+/// extensions for the mocks.
+#[derive(Clone, Default)]
+pub(crate) struct RequestCancelled(Arc<AtomicBool>);
+
+impl RequestCancelled {
+    /// The error to record for the request stream if it was cancelled, this is only known once
+    /// the trailers have been read.
+    pub(crate) fn status(&self) -> Option<Status> {
+        self.0
+            .load(Ordering::SeqCst)
+            .then(|| Status::cancelled("The client cancelled the request"))
+    }
+}
+
+/// A request body which notes the client resetting the stream with `CANCEL`. The reset only
+/// shows up as an error when polling for the trailers after the data.
+struct CancellableBody {
+    inner: Body,
+    cancelled: RequestCancelled,
+}
+
+impl CancellableBody {
+    fn is_cancel(error: &hyper::Error) -> bool {
+        let mut source = std::error::Error::source(error);
+        while let Some(error) = source {
+            if let Some(error) = error.downcast_ref::<h2::Error>() {
+                return error.is_remote() && error.reason() == Some(h2::Reason::CANCEL);
+            }
+            source = error.source();
+        }
+        false
+    }
+}
+
+impl http_body::Body for CancellableBody {
+    type Data = Bytes;
+    type Error = Status;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        Pin::new(&mut self.inner)
+            .poll_data(cx)
+            .map_err(|e| Status::from_error(e.into()))
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<http::HeaderMap>, Self::Error>> {
+        let trailers = ready!(Pin::new(&mut self.inner).poll_trailers(cx));
+        Poll::Ready(trailers.map_err(|e| {
+            if Self::is_cancel(&e) {
+                self.cancelled.0.store(true, Ordering::SeqCst);
+            }
+            Status::from_error(e.into())
+        }))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
     }
 }
 
+impl Service<http::Request<Body>> for MockRouter {
+    type Response = http::Response<FaultBody>;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.faults.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http::Request<Body>) -> Self::Future {
+        let cancelled = RequestCancelled::default();
+        let mut req = req.map(|inner| {
+            CancellableBody {
+                inner,
+                cancelled: cancelled.clone(),
+            }
+            .boxed_unsync()
+        });
+        req.extensions_mut().insert(cancelled);
+        if let Some(info) = self.connect_info.clone() {
+            req.extensions_mut().insert(info);
+        }
+        let name = req.uri().path().split('/').nth(1).unwrap_or_default();
+        let service = self.services.lock().unwrap().get(name).cloned();
+        let faults = Arc::new(RequestFaults::new(Arc::clone(&self.faults)));
+        let response = match service {
+            Some(service) => service.oneshot(req),
+            None => {
+                debug!("No service registered for {}", req.uri().path());
+                let status = Status::unimplemented(format!("{} is not mocked", name));
+                let response = status.to_http().map(|body| FaultBody::new(body, faults));
+                return Box::pin(futures::future::ready(Ok(response)));
+            }
+        };
+        Box::pin(async move {
+            let response = fault::scope(Arc::clone(&faults), response)
+                .await
+                .unwrap_or_else(|e| match e {});
+            apply_fault(response, faults).await
+        })
+    }
+}
+
+/// Handle to a mock service running on its own server. Dropping the handle shuts the server
+/// down.
+pub struct ServerHandle {
+    addr: SocketAddr,
+    // Never sent on, dropping it is what shuts the server down
+    _shutdown: oneshot::Sender<()>,
+}
+
+impl ServerHandle {
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+/// Serve a single tonic service on an OS assigned port on localhost. This is what the generated
+/// mock services use to run themselves.
+pub async fn serve<S>(service: S) -> ServerHandle
+where
+    S: Service<http::Request<BoxBody>, Response = http::Response<BoxBody>, Error = Infallible>
+        + NamedService
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Failed to bind an OS port for a mock server.");
+    let addr = listener.local_addr().unwrap();
+    info!("Bound to: {:?}", addr);
+    let router = MockRouter::default();
+    router.register(service);
+
+    let (tx, rx) = oneshot::channel();
+
+    // Once bound the OS queues incoming connections, so the server is ready as soon as it has
+    // the listener.
+    tokio::spawn(async move {
+        info!("Creating server");
+        let shutdown = async move {
+            let _ = rx.await;
+        };
+        if let Err(e) = run(listener, router, shutdown).await {
+            info!("Mock server on {} exited with error: {}", addr, e);
+        }
+        info!("Server closing down");
+    });
+
+    ServerHandle {
+        addr,
+        _shutdown: tx,
+    }
+}
+
+/// Serve the router over HTTP/2 until `shutdown` completes. This uses hyper rather than a tonic
+/// server as tonic enforces `grpc-timeout` itself, this way it's up to the mocks.
+async fn run(
+    listener: TcpListener,
+    router: MockRouter,
+    shutdown: impl Future<Output = ()>,
+) -> hyper::Result<()> {
+    let mut incoming = AddrIncoming::from_listener(listener)?;
+    incoming.set_nodelay(true);
+    // Wrap the connections so a fault can close them
+    let incoming = accept::poll_fn(move |cx| {
+        Pin::new(&mut incoming)
+            .poll_accept(cx)
+            .map_ok(FaultyConnection::new)
+    });
+    let make_service = make_service_fn(move |conn: &FaultyConnection| {
+        let router = router.for_connection(conn);
+        async move { Ok::<_, Infallible>(router) }
+    });
+    hyper::Server::builder(incoming)
+        .http2_only(true)
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown)
+        .await
+}
+
+/// A server which can have any services registered on it, these are what's kept in the pool.
 pub(crate) struct GrpcMockServer {
-    listener: TcpIncoming,
+    addr: SocketAddr,
+    router: MockRouter,
+    shutdown: Option<oneshot::Sender<()>>,
 }
 
 impl GrpcMockServer {
     async fn start() -> Self {
-        let listener = TcpListener::bind("127.0.0.1:0")
-            .await
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")
             .expect("Failed to bind an OS port for a mock server.");
+        listener
+            .set_nonblocking(true)
+            .expect("Failed to set the mock server listener to non-blocking.");
+        let addr = listener.local_addr().unwrap();
+        let router = MockRouter::default();
+        let (shutdown, rx) = oneshot::channel();
+        let (ready_tx, ready_rx) = oneshot::channel();
 
-        // let local = listener.local_addr();
+        // Each tokio test has its own runtime and the pooled servers outlive the test that
+        // started them, so they have to run on their own runtime.
+        let to_serve = router.clone();
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("Failed to build a runtime for the mock server.");
+            runtime.block_on(async move {
+                let listener = TcpListener::from_std(listener).unwrap();
+                let shutdown = async move {
+                    let _ = rx.await;
+                };
+                let _ = ready_tx.send(());
+                if let Err(e) = run(listener, to_serve, shutdown).await {
+                    info!("Mock server on {} exited with error: {}", addr, e);
+                }
+            });
+        });
 
-        todo!()
-    }
+        ready_rx.await.expect("Mock server failed to start");
 
-    pub fn serve(&self) {}
+        Self {
+            addr,
+            router,
+            shutdown: Some(shutdown),
+        }
+    }
+}
 
-    pub async fn reset(&self) {}
+impl Drop for GrpcMockServer {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+    }
 }
 
-/// The `BareMockServer` pool manager.
+/// The `GrpcMockServer` pool manager.
 ///
 /// It:
-/// - creates a new `BareMockServer` if there is none to borrow from the pool;
-/// - "cleans up" used `BareMockServer`s before making them available again for other tests to use.
+/// - creates a new `GrpcMockServer` if there is none to borrow from the pool;
+/// - hands used `GrpcMockServer`s out again as they are, `MockServer` removes the services when
+///   it's dropped.
 pub(crate) struct MockServerPoolManager;
 
 #[async_trait]
@@ -75,11 +444,8 @@ impl deadpool::managed::Manager for MockServerPoolManager {
 
     async fn recycle(
         &self,
-        mock_server: &mut GrpcMockServer,
+        _mock_server: &mut GrpcMockServer,
     ) -> deadpool::managed::RecycleResult<Infallible> {
-        // Remove all existing settings - we want to start clean when the mock server
-        // is picked up again from the pool.
-        mock_server.reset().await;
         Ok(())
     }
 }
@@ -0,0 +1,208 @@
+//! Reports on whether the expectations set on the mocks were met, these implement `Display` so
+//! they can be used in assertion messages:
+//!
+//! ```ignore
+//! let report = server.verify().await;
+//! assert!(report.is_success(), "{}", report);
+//! ```
+use crate::times::Times;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// The verification results for every method mock in a mock service.
+#[derive(Clone, Debug, Default)]
+pub struct VerificationReport {
+    pub methods: Vec<MethodReport>,
+    /// Calls to methods which weren't mocked. These don't fail verification as the client got an
+    /// `UNIMPLEMENTED` status back, but they're often a sign something is wrong.
+    pub unmocked_calls: Vec<UnmockedCall>,
+}
+
+impl VerificationReport {
+    pub fn is_success(&self) -> bool {
+        self.methods.iter().all(|x| x.is_success())
+    }
+}
+
+impl fmt::Display for VerificationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_success() {
+            writeln!(f, "All expectations met")?;
+        } else {
+            writeln!(f, "Verification failed:")?;
+        }
+        for method in self.methods.iter().filter(|x| !x.is_success()) {
+            write!(f, "{}", method)?;
+        }
+        if !self.unmocked_calls.is_empty() {
+            writeln!(f, "Unmocked calls:")?;
+            for call in &self.unmocked_calls {
+                writeln!(f, "- {}: {}", call.method, call.request)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The verification results for a single method mock.
+#[derive(Clone, Debug)]
+pub struct MethodReport {
+    pub method: String,
+    pub expected_calls: Option<Times>,
+    pub calls: u64,
+    /// Every time a matcher rejected a request
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl MethodReport {
+    pub fn calls_met(&self) -> bool {
+        self.expected_calls
+            .as_ref()
+            .map(|x| x.contains(self.calls))
+            .unwrap_or(true)
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.calls_met() && self.mismatches.is_empty()
+    }
+}
+
+impl fmt::Display for MethodReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.expected_calls.as_ref() {
+            Some(expected) if !self.calls_met() => writeln!(
+                f,
+                "- {}: expected calls {}, received {}",
+                self.method, expected, self.calls
+            )?,
+            _ => writeln!(f, "- {}: received {} calls", self.method, self.calls)?,
+        }
+        for mismatch in &self.mismatches {
+            writeln!(
+                f,
+                "    `{}` rejected request: {}",
+                mismatch.matcher, mismatch.request
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A matcher which rejected a request.
+#[derive(Clone, Debug)]
+pub struct Mismatch {
+    /// Description of the matcher
+    pub matcher: String,
+    /// Debug rendering of the request, including the metadata
+    pub request: String,
+}
+
+/// A call to a method without a mock.
+#[derive(Clone, Debug)]
+pub struct UnmockedCall {
+    pub method: String,
+    /// Debug rendering of the request, including the metadata
+    pub request: String,
+}
+
+/// Shared list of the unmocked calls a mock service receives.
+#[derive(Clone, Debug, Default)]
+pub struct UnmockedCalls(Arc<Mutex<Vec<UnmockedCall>>>);
+
+impl UnmockedCalls {
+    pub fn record(&self, method: &str, request: &impl fmt::Debug) {
+        self.0.lock().unwrap().push(UnmockedCall {
+            method: method.to_string(),
+            request: format!("{:?}", request),
+        });
+    }
+
+    pub fn get(&self) -> Vec<UnmockedCall> {
+        self.0.lock().unwrap().clone()
+    }
+
+    pub fn clear(&self) {
+        self.0.lock().unwrap().clear();
+    }
+}
+
+/// Verifies a mock when it's dropped and panics with the report if the expectations weren't met,
+/// like wiremock does. The generated mock services share one of these between all the clones held
+/// by the test, so verification happens once the test is done with the mock.
+pub struct DropVerifier {
+    verify: Box<dyn Fn() -> VerificationReport + Send + Sync>,
+    enabled: AtomicBool,
+}
+
+impl DropVerifier {
+    pub fn new(verify: impl Fn() -> VerificationReport + Send + Sync + 'static) -> Self {
+        Self {
+            verify: Box::new(verify),
+            enabled: AtomicBool::new(true),
+        }
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::SeqCst);
+    }
+}
+
+impl Drop for DropVerifier {
+    fn drop(&mut self) {
+        // Don't panic while panicking, the test has already failed
+        if !self.enabled.load(Ordering::SeqCst) || std::thread::panicking() {
+            return;
+        }
+        let report = (self.verify)();
+        if !report.is_success() {
+            panic!("Mock expectations weren't met on drop.\n{}", report);
+        }
+    }
+}
+
+/// Keeps track of the calls to a method mock and the expectations for them. Clones share the
+/// calls and mismatches, so they can be recorded from a task handling the request.
+#[derive(Clone, Default)]
+pub(crate) struct CallRecorder {
+    name: String,
+    called: Arc<AtomicU64>,
+    expected_calls: Option<Times>,
+    // Streaming matchers can finish after the request is processed so this is shared with them
+    mismatches: Arc<Mutex<Vec<Mismatch>>>,
+}
+
+impl CallRecorder {
+    pub(crate) fn set_name(&mut self, name: String) {
+        self.name = name;
+    }
+
+    pub(crate) fn expect(&mut self, calls: Times) {
+        self.expected_calls = Some(calls);
+    }
+
+    pub(crate) fn called(&self) {
+        self.called.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn mismatch(&self, matcher: String, request: String) {
+        self.mismatches
+            .lock()
+            .unwrap()
+            .push(Mismatch { matcher, request });
+    }
+
+    pub(crate) fn reset(&self) {
+        self.called.store(0, Ordering::SeqCst);
+        self.mismatches.lock().unwrap().clear();
+    }
+
+    pub(crate) fn report(&self) -> MethodReport {
+        MethodReport {
+            method: self.name.clone(),
+            expected_calls: self.expected_calls.clone(),
+            calls: self.called.load(Ordering::SeqCst),
+            mismatches: self.mismatches.lock().unwrap().clone(),
+        }
+    }
+}
@@ -0,0 +1,40 @@
+use dummy::mock_client::MockClient;
+use dummy::mock_server::MockMock;
+use dummy::Empty;
+use tonic::{Code, Request};
+use tonic_mock::prelude::*;
+use tracing_test::traced_test;
+
+pub mod dummy {
+    tonic::include_proto!("tonic_mock.dummy");
+}
+
+/// The dummy package is only mocked via the package discovery in `tonic_mock_build`
+#[tokio::test]
+#[traced_test]
+async fn discovered_packages_are_mocked() {
+    let mut mock = MockMock::build();
+
+    mock.mock_unary()
+        .expect(1)
+        .response(FixedResponse::default_ok());
+
+    let server = mock.build();
+    server.serve().await;
+
+    let addr = server.listening_address().await.unwrap();
+    let mut client = MockClient::connect(addr).await.unwrap();
+
+    client.unary(Request::new(Empty {})).await.unwrap();
+
+    let status = client
+        .server_stream(Request::new(Empty {}))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::Unimplemented);
+
+    let report = server.verify().await;
+    assert!(report.is_success(), "{}", report);
+    assert_eq!(report.unmocked_calls.len(), 1);
+    assert_eq!(report.unmocked_calls[0].method, "server_stream");
+}
@@ -1,20 +1,12 @@
-use futures::stream::{self, Stream};
+#![allow(clippy::result_large_err)]
+
+use futures::stream;
 use routeguide::route_guide_client::RouteGuideClient;
-use routeguide::route_guide_server::{RouteGuide, RouteGuideServer};
+use routeguide::route_guide_server::MockRouteGuide;
 use routeguide::{Feature, Point, Rectangle, RouteNote, RouteSummary};
-use std::error::Error;
-use std::net::SocketAddr;
-use std::pin::Pin;
-use std::sync::Arc;
 use std::time::Duration;
-use tokio::net::TcpListener;
-use tokio::sync::mpsc;
-use tokio::sync::RwLock;
-use tokio::time;
-use tonic::transport::server::{Server, TcpIncoming};
-use tonic::transport::Channel;
-use tonic::Status;
-use tonic::{Request, Response};
+use tonic::metadata::{MetadataMap, MetadataValue};
+use tonic::{Code, Request, Response, Status};
 use tonic_mock::prelude::*;
 use tracing::info;
 use tracing_test::traced_test;
@@ -23,148 +15,57 @@ pub mod routeguide {
     tonic::include_proto!("routeguide");
 }
 
-fn make_mock_server() {}
-
-#[derive(Default)]
-pub struct MockRouteGuideServiceBuilder {
-    get_feature_mock: Option<UnaryMethodMock<Point, Feature>>,
-}
-
-#[derive(Default, Clone)]
-pub struct MockRouteGuideService {
-    get_feature_mock: Arc<RwLock<Option<UnaryMethodMock<Point, Feature>>>>,
-    server_handle: Arc<RwLock<Option<Handle>>>,
-}
-
-pub struct Handle {
-    addr: SocketAddr,
-    tx: mpsc::Sender<()>,
-}
-
-impl Drop for Handle {
-    fn drop(&mut self) {
-        let tx = self.tx.clone();
-        tokio::spawn(async move {
-            tx.send(()).await.unwrap();
-        });
-    }
-}
-
-impl MockRouteGuideServiceBuilder {
-    fn mock_get_feature(&mut self) -> &mut UnaryMethodMock<Point, Feature> {
-        self.get_feature_mock = Some(UnaryMethodMock::default());
-        self.get_feature_mock.as_mut().unwrap()
-    }
-
-    fn build(self) -> MockRouteGuideService {
-        MockRouteGuideService {
-            get_feature_mock: Arc::new(RwLock::new(self.get_feature_mock)),
-            server_handle: Arc::default(),
-        }
-    }
-}
-
-impl MockRouteGuideService {
-    pub fn build() -> MockRouteGuideServiceBuilder {
-        Default::default()
-    }
-
-    pub async fn serve(&self) {
-        let listener = TcpListener::bind("127.0.0.1:0")
-            .await
-            .expect("Failed to bind an OS port for a mock server.");
-        let addr = listener.local_addr().unwrap();
-        info!("Bound to: {:?}", addr);
-
-        let to_serve = self.clone();
-
-        let (tx, mut rx) = mpsc::channel(1);
-
-        let server = tokio::spawn(async move {
-            info!("Creating listener");
-            let listener = TcpIncoming::from_listener(listener, true, None).unwrap();
-            info!("Creating server");
-            Server::builder()
-                .add_service(RouteGuideServer::new(to_serve))
-                .serve_with_incoming_shutdown(listener, async move {
-                    let _ = rx.recv().await;
-                })
-                .await
-                .unwrap();
-
-            info!("Server closing down");
-        });
-
-        time::sleep(time::Duration::from_secs(1)).await;
-
-        info!("Returning handle");
-        self.server_handle.write().await.insert(Handle { tx, addr });
-    }
+struct CountPoints;
 
-    pub async fn verify(&self) -> bool {
-        let read = self.get_feature_mock.read().await;
-        if let Some(x) = read.as_ref() {
-            x.verify()
-        } else {
-            true
-        }
-    }
+/// Rejects the stream with the error it ended with so the client can see it was passed on.
+struct RejectErrors;
 
-    pub async fn reset(&self) {
-        if let Some(x) = self.get_feature_mock.write().await.as_mut() {
-            x.reset()
+impl StreamingResponder<Point, RouteSummary> for RejectErrors {
+    fn response(
+        &self,
+        header: &MetadataMap,
+        messages: &[Point],
+        trailers: Option<&MetadataMap>,
+        error: Option<&Status>,
+    ) -> Result<Response<RouteSummary>, Status> {
+        match error {
+            Some(status) => Err(Status::invalid_argument(format!(
+                "stream failed: {}",
+                status.message()
+            ))),
+            None => CountPoints.response(header, messages, trailers, None),
         }
     }
-
-    pub async fn listening_address(&self) -> Option<String> {
-        let r = self.server_handle.read().await;
-        r.as_ref().map(|x| format!("http://{}", x.addr))
-    }
 }
 
-#[tonic::async_trait]
-impl RouteGuide for MockRouteGuideService {
-    async fn get_feature(&self, request: Request<Point>) -> Result<Response<Feature>, Status> {
-        info!("Got request");
-        if let Some(s) = self.get_feature_mock.read().await.as_ref() {
-            s.process_request(request)
-        } else {
-            Err(tonic::Status::unimplemented(
-                "get_feature is not implemented",
-            ))
-        }
-    }
-
-    type ListFeaturesStream = Pin<Box<dyn Stream<Item = Result<Feature, Status>> + Send + 'static>>;
-
-    async fn list_features(
-        &self,
-        request: Request<Rectangle>,
-    ) -> Result<Response<Self::ListFeaturesStream>, Status> {
-        todo!()
-    }
-
-    async fn record_route(
+impl StreamingResponder<Point, RouteSummary> for CountPoints {
+    fn response(
         &self,
-        request: Request<tonic::Streaming<Point>>,
+        _header: &MetadataMap,
+        messages: &[Point],
+        _trailers: Option<&MetadataMap>,
+        _error: Option<&Status>,
     ) -> Result<Response<RouteSummary>, Status> {
-        todo!()
+        Ok(Response::new(RouteSummary {
+            point_count: messages.len() as i32,
+            ..Default::default()
+        }))
     }
+}
 
-    type RouteChatStream = Pin<Box<dyn Stream<Item = Result<RouteNote, Status>> + Send + 'static>>;
-
-    async fn route_chat(
-        &self,
-        request: Request<tonic::Streaming<RouteNote>>,
-    ) -> Result<Response<Self::RouteChatStream>, Status> {
-        todo!()
-    }
+/// A client which leaves deadlines to the server, a tonic `Channel` enforces them itself so the
+/// test wouldn't see what the mock does.
+fn client_without_deadlines(
+    addr: &str,
+) -> RouteGuideClient<hyper::Client<hyper::client::HttpConnector, tonic::body::BoxBody>> {
+    let client = hyper::Client::builder().http2_only(true).build_http();
+    RouteGuideClient::with_origin(client, addr.parse().unwrap())
 }
 
 #[tokio::test]
 #[traced_test]
 async fn check_mocked_route_guide() {
-    let mut mock = MockRouteGuideService::build();
+    let mut mock = MockRouteGuide::build();
 
     mock.mock_get_feature()
         .add_matcher(MetadataExistsMatcher::new("grpc-trace".into()))
@@ -196,7 +97,8 @@ async fn check_mocked_route_guide() {
     client.get_feature(req).await.unwrap();
 
     info!("Verifying result");
-    assert!(server.verify().await);
+    let report = server.verify().await;
+    assert!(report.is_success(), "{}", report);
 
     info!("Reset mock server state");
     server.reset().await;
@@ -206,7 +108,1674 @@ async fn check_mocked_route_guide() {
         latitude: 2,
         longitude: 2,
     });
-    client.get_feature(req).await.unwrap();
+    let status = client.get_feature(req).await.unwrap_err();
+    assert_eq!(status.code(), Code::NotFound);
+
+    let report = server.verify().await;
+    assert!(!report.is_success());
+    server.disable_verify_on_drop();
+    let mismatch = &report.methods[0].mismatches[0];
+    assert_eq!(report.methods[0].method, "get_feature");
+    assert!(mismatch.matcher.contains("grpc-trace"));
+    assert!(mismatch.request.contains("latitude: 2"));
+}
+
+#[tokio::test]
+#[traced_test]
+async fn check_mocked_record_route() {
+    let mut mock = MockRouteGuide::build();
+
+    mock.mock_record_route()
+        .add_matcher(MetadataExistsMatcher::new("grpc-trace".into()))
+        .expect(1)
+        .response(CountPoints);
+
+    let server = mock.build();
+    server.serve().await;
+
+    let addr = server.listening_address().await.unwrap();
+    let mut client = RouteGuideClient::connect(addr).await.unwrap();
+
+    let points = (0..3).map(|x| Point {
+        latitude: x,
+        longitude: x,
+    });
+    let mut req = Request::new(stream::iter(points));
+    req.metadata_mut()
+        .insert("grpc-trace", "trace me".try_into().unwrap());
+
+    let summary = client.record_route(req).await.unwrap().into_inner();
+    assert_eq!(summary.point_count, 3);
+    let report = server.verify().await;
+    assert!(report.is_success(), "{}", report);
+
+    server.reset().await;
+
+    let req = Request::new(stream::iter(vec![Point::default()]));
+    let status = client.record_route(req).await.unwrap_err();
+    assert_eq!(status.code(), Code::NotFound);
+    assert!(!server.verify().await.is_success());
+    server.disable_verify_on_drop();
+}
+
+#[tokio::test]
+#[traced_test]
+async fn check_mocked_list_features() {
+    let mut mock = MockRouteGuide::build();
+
+    let feature = |name: &str| Feature {
+        name: name.to_string(),
+        location: None,
+    };
+    let mut trailers = MetadataMap::new();
+    trailers.insert("x-next-page", "2".parse().unwrap());
+
+    mock.mock_list_features().expect(1).response(
+        StreamResponse::new(vec![Ok(feature("Everest")), Ok(feature("K2"))])
+            .delayed_message(feature("Lhotse"), Duration::from_millis(50))
+            .trailers(trailers),
+    );
+
+    let server = mock.build();
+    server.serve().await;
+
+    let addr = server.listening_address().await.unwrap();
+    let mut client = RouteGuideClient::connect(addr).await.unwrap();
+
+    let mut stream = client
+        .list_features(Request::new(Rectangle::default()))
+        .await
+        .unwrap()
+        .into_inner();
+
+    let mut names = vec![];
+    while let Some(feature) = stream.message().await.unwrap() {
+        names.push(feature.name);
+    }
+    assert_eq!(names, ["Everest", "K2", "Lhotse"]);
+
+    let trailers = stream.trailers().await.unwrap().unwrap();
+    assert_eq!(trailers.get("x-next-page").unwrap(), "2");
+
+    let report = server.verify().await;
+    assert!(report.is_success(), "{}", report);
+}
+
+#[tokio::test]
+#[traced_test]
+async fn check_list_features_terminal_status() {
+    let mut mock = MockRouteGuide::build();
+
+    mock.mock_list_features().response(
+        StreamResponse::default()
+            .message(Feature::default())
+            .status(Status::resource_exhausted("Out of features")),
+    );
+
+    let server = mock.build();
+    server.serve().await;
+
+    let addr = server.listening_address().await.unwrap();
+    let mut client = RouteGuideClient::connect(addr).await.unwrap();
+
+    let mut stream = client
+        .list_features(Request::new(Rectangle::default()))
+        .await
+        .unwrap()
+        .into_inner();
+
+    assert!(stream.message().await.unwrap().is_some());
+    let status = stream.message().await.unwrap_err();
+    assert_eq!(status.code(), Code::ResourceExhausted);
+}
+
+#[tokio::test(start_paused = true)]
+async fn item_delay_applies_to_every_message() {
+    use futures::StreamExt;
+
+    let feature = |name: &str| Feature {
+        name: name.to_string(),
+        location: None,
+    };
+    let response = StreamResponse::default()
+        .item_delay(Duration::from_secs(1))
+        .message(feature("Everest"))
+        .delayed_message(feature("K2"), Duration::from_secs(5))
+        .message(feature("Lhotse"))
+        .status(Status::resource_exhausted("Out of features"));
+
+    let start = tokio::time::Instant::now();
+    let stream = ServerStreamResponder::<Rectangle, Feature>::respond(
+        &response,
+        Request::new(Rectangle::default()),
+    )
+    .unwrap()
+    .into_inner();
+    let sent = stream
+        .map(|x| (start.elapsed().as_secs(), x.is_ok()))
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(sent, [(1, true), (6, true), (7, true), (7, false)]);
+}
+
+#[tokio::test]
+#[traced_test]
+async fn check_mocked_route_chat() {
+    let mut mock = MockRouteGuide::build();
+
+    let note = |message: &str| RouteNote {
+        location: None,
+        message: message.to_string(),
+    };
+
+    mock.mock_route_chat()
+        .add_matcher(MetadataExistsMatcher::new("grpc-trace".into()))
+        .expect(1)
+        .response(
+            Conversation::new()
+                .when(|x: &RouteNote| x.message == "ping", note("pong"))
+                .after(3, note("three"))
+                .on_close(Status::aborted("bye")),
+        );
+
+    let server = mock.build();
+    server.serve().await;
+
+    let addr = server.listening_address().await.unwrap();
+    let mut client = RouteGuideClient::connect(addr).await.unwrap();
+
+    let notes = vec![note("ping"), note("hello"), note("ping")];
+    let mut req = Request::new(stream::iter(notes));
+    req.metadata_mut()
+        .insert("grpc-trace", "trace me".try_into().unwrap());
+
+    let mut stream = client.route_chat(req).await.unwrap().into_inner();
+
+    let mut replies = vec![];
+    let status = loop {
+        match stream.message().await {
+            Ok(Some(note)) => replies.push(note.message),
+            Ok(None) => panic!("Stream should end with the close status"),
+            Err(status) => break status,
+        }
+    };
+    assert_eq!(replies, ["pong", "pong", "three"]);
+    assert_eq!(status.code(), Code::Aborted);
+
+    let report = server.verify().await;
+    assert!(report.is_success(), "{}", report);
+}
+
+#[tokio::test]
+#[traced_test]
+async fn check_pooled_mock_server() {
+    let mut mock = MockRouteGuide::build();
+
+    mock.mock_get_feature()
+        .expect(1)
+        .response(FixedResponse::ok(Feature::default()));
+
+    let mock = mock.build();
+    let server = MockServer::start().await;
+    mock.mount(&server);
+
+    let mut client = RouteGuideClient::connect(server.uri()).await.unwrap();
+    client
+        .get_feature(Request::new(Point::default()))
+        .await
+        .unwrap();
+
+    let status = client
+        .list_features(Request::new(Rectangle::default()))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::Unimplemented);
+
+    let report = mock.verify().await;
+    assert!(report.is_success(), "{}", report);
+    std::mem::drop(server);
+
+    // Services are removed as soon as the server goes back into the pool
+    let status = client
+        .get_feature(Request::new(Point::default()))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::Unimplemented);
+
+    let server = MockServer::start().await;
+    let mut client = RouteGuideClient::connect(server.uri()).await.unwrap();
+    let status = client
+        .get_feature(Request::new(Point::default()))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::Unimplemented);
+}
+
+#[tokio::test]
+#[should_panic(expected = "get_feature: expected calls")]
+async fn unmet_expectations_panic_on_drop() {
+    let mut mock = MockRouteGuide::build();
+    mock.mock_get_feature()
+        .expect(1)
+        .response(FixedResponse::default_ok());
+
+    let server = mock.build();
+    server.serve().await;
+    // Clones held by the test don't verify until the last one is dropped
+    drop(server.clone());
+}
+
+#[tokio::test]
+async fn verify_on_drop_opt_out() {
+    let mut mock = MockRouteGuide::build();
+    mock.mock_get_feature()
+        .expect(1)
+        .response(FixedResponse::default_ok());
+    mock.verify_on_drop(false);
+
+    let server = mock.build();
+    server.serve().await;
+}
+
+#[tokio::test]
+async fn dropping_the_mock_stops_its_server() {
+    let server = MockRouteGuide::default();
+    server.serve().await;
+    let addr = server.listening_address().await.unwrap();
+    RouteGuideClient::connect(addr.clone()).await.unwrap();
+
+    drop(server);
+    // The server shuts down once it sees the handle has gone
+    let stopped = async {
+        while RouteGuideClient::connect(addr.clone()).await.is_ok() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(5), stopped)
+        .await
+        .expect("server still accepting connections");
+}
+
+#[tokio::test]
+async fn requests_are_routed_between_mocks() {
+    let mut mock = MockRouteGuide::build();
+    let feature = |name: &str| Feature {
+        name: name.to_string(),
+        location: None,
+    };
+
+    mock.mock_get_feature()
+        .add_matcher(MetadataExistsMatcher::new("x-first".into()))
+        .expect(0)
+        .response(FixedResponse::ok(feature("first")));
+    mock.mock_get_feature()
+        .add_matcher(MetadataExistsMatcher::new("x-second".into()))
+        .expect(1)
+        .response(FixedResponse::ok(feature("second")));
+    mock.mock_get_feature()
+        .add_matcher(MetadataExistsMatcher::new("x-first".into()))
+        .priority(1)
+        .expect(1)
+        .response(FixedResponse::ok(feature("priority")));
+    mock.get_feature_mocks()
+        .no_match(Status::failed_precondition("no match"));
+
+    let server = mock.build();
+    server.serve().await;
+    let addr = server.listening_address().await.unwrap();
+    let mut client = RouteGuideClient::connect(addr).await.unwrap();
+
+    let request = |key: &'static str| {
+        let mut req = Request::new(Point::default());
+        req.metadata_mut().insert(key, "".try_into().unwrap());
+        req
+    };
+
+    let response = client.get_feature(request("x-second")).await.unwrap();
+    assert_eq!(response.into_inner().name, "second");
+    let response = client.get_feature(request("x-first")).await.unwrap();
+    assert_eq!(response.into_inner().name, "priority");
+
+    let report = server.verify().await;
+    assert!(report.is_success(), "{}", report);
+
+    let status = client.get_feature(request("x-other")).await.unwrap_err();
+    assert_eq!(status.code(), Code::FailedPrecondition);
+
+    let report = server.verify().await;
+    assert!(!report.is_success());
+    assert_eq!(report.methods.len(), 3);
+    assert_eq!(report.methods[1].method, "get_feature #2");
+    assert!(report.methods.iter().all(|x| x.mismatches.len() == 1));
+    server.disable_verify_on_drop();
+}
+
+#[tokio::test]
+async fn client_streams_are_routed_between_mocks() {
+    let mut mock = MockRouteGuide::build();
+    let summary = |distance| RouteSummary {
+        distance,
+        ..Default::default()
+    };
+
+    mock.mock_record_route()
+        .add_matcher(MessageCount::new(0))
+        .expect(1)
+        .response(FixedResponse::ok(summary(0)));
+    mock.mock_record_route()
+        .expect(1)
+        .response(FixedResponse::ok(summary(1)));
+    mock.mock_record_route()
+        .add_matcher(MessageCount::new(2))
+        .priority(1)
+        .expect(1)
+        .response(FixedResponse::ok(summary(2)));
+
+    let server = mock.build();
+    server.serve().await;
+    let addr = server.listening_address().await.unwrap();
+    let mut client = RouteGuideClient::connect(addr).await.unwrap();
+
+    for count in 0..3 {
+        let points = stream::iter(vec![Point::default(); count]);
+        let response = client.record_route(points).await.unwrap();
+        assert_eq!(response.into_inner().distance, count as i32);
+    }
+
+    let report = server.verify().await;
+    assert!(report.is_success(), "{}", report);
+    assert_eq!(server.record_route_requests().await.len(), 3);
+}
+
+#[tokio::test]
+async fn bidirectional_streams_are_routed_on_headers() {
+    let mut mock = MockRouteGuide::build();
+    let note = |message: &str| RouteNote {
+        location: None,
+        message: message.to_string(),
+    };
+
+    mock.mock_route_chat()
+        .add_matcher(MetadataEqMatcher::header("x-room".into(), "a".into()))
+        .expect(1)
+        .response(Conversation::new().after(1, note("room a")));
+    mock.mock_route_chat()
+        .add_matcher(MetadataEqMatcher::header("x-room".into(), "b".into()))
+        // Only checked once the stream ends, so this doesn't choose the mock
+        .add_matcher(MessageCount::new(2))
+        .expect(1)
+        .response(Conversation::new().after(1, note("room b")));
+
+    let server = mock.build();
+    server.serve().await;
+    let addr = server.listening_address().await.unwrap();
+    let mut client = RouteGuideClient::connect(addr).await.unwrap();
+
+    for room in ["a", "b"] {
+        let mut req = Request::new(stream::iter(vec![RouteNote::default()]));
+        req.metadata_mut().insert("x-room", room.parse().unwrap());
+        let mut replies = client.route_chat(req).await.unwrap().into_inner();
+        let reply = replies.message().await.unwrap().unwrap();
+        assert_eq!(reply.message, format!("room {}", room));
+        while replies.message().await.unwrap().is_some() {}
+    }
+
+    let report = server.verify().await;
+    assert!(report.methods[0].is_success(), "{}", report);
+    let mismatches = &report.methods[1].mismatches;
+    assert_eq!(mismatches.len(), 1, "{}", report);
+    assert_eq!(mismatches[0].matcher, "message count == 2");
+
+    let notes = stream::iter(vec![RouteNote::default()]);
+    let status = client.route_chat(notes).await.unwrap_err();
+    assert_eq!(status.code(), Code::NotFound);
+    server.disable_verify_on_drop();
+}
+
+#[tokio::test]
+async fn body_matchers_select_the_response() {
+    let mut mock = MockRouteGuide::build();
+    let feature = |name: &str| Feature {
+        name: name.to_string(),
+        location: None,
+    };
+
+    mock.mock_get_feature()
+        .add_matcher(FieldEq::int([1], 28))
+        .expect(1)
+        .response(FixedResponse::ok(feature("Mount Everest")));
+    mock.mock_get_feature()
+        .add_matcher(predicate(|point: &Point| point.latitude < 0).describe("southern"))
+        .expect(1)
+        .response(FixedResponse::ok(feature("south")));
+    mock.mock_get_feature()
+        .add_matcher(BodyEq(Point::default()))
+        .expect(1)
+        .response(FixedResponse::ok(feature("origin")));
+    mock.mock_list_features()
+        .add_matcher(FieldEq::int([2, 1], -10))
+        .expect(1)
+        .response(StreamResponse::default().message(feature("in the box")));
+
+    let server = mock.build();
+    server.serve().await;
+    let addr = server.listening_address().await.unwrap();
+    let mut client = RouteGuideClient::connect(addr).await.unwrap();
+
+    let point = |latitude| Point {
+        latitude,
+        longitude: 87,
+    };
+    let name = |response: Response<Feature>| response.into_inner().name;
+    assert_eq!(
+        name(client.get_feature(point(28)).await.unwrap()),
+        "Mount Everest"
+    );
+    assert_eq!(name(client.get_feature(point(-5)).await.unwrap()), "south");
+    assert_eq!(
+        name(client.get_feature(Point::default()).await.unwrap()),
+        "origin"
+    );
+    let status = client.get_feature(point(5)).await.unwrap_err();
+    assert_eq!(status.code(), Code::NotFound);
+
+    let rect = Rectangle {
+        lo: None,
+        hi: Some(point(-10)),
+    };
+    let mut features = client.list_features(rect).await.unwrap().into_inner();
+    assert_eq!(
+        features.message().await.unwrap().unwrap().name,
+        "in the box"
+    );
+
+    let report = server.verify().await;
+    let mismatches = report
+        .methods
+        .iter()
+        .flat_map(|x| x.mismatches.iter().map(|x| x.matcher.as_str()))
+        .collect::<Vec<_>>();
+    assert_eq!(mismatches.len(), 3, "{}", report);
+    assert!(mismatches.contains(&"field [1] equals Varint(28)"));
+    assert!(mismatches.contains(&"southern"));
+    server.disable_verify_on_drop();
+}
+
+#[tokio::test]
+async fn metadata_value_matchers() {
+    let mut mock = MockRouteGuide::build();
+    let feature = |name: &str| Feature {
+        name: name.to_string(),
+        location: None,
+    };
+
+    // Unary requests don't have trailers so this is never picked
+    mock.mock_get_feature()
+        .add_matcher(MetadataEqMatcher::trailer("tenant".into(), "acme".into()))
+        .priority(1)
+        .expect(0)
+        .response(FixedResponse::ok(feature("never")));
+    mock.mock_get_feature()
+        .add_matcher(MetadataEqMatcher::header("tenant".into(), "acme".into()))
+        .expect(1)
+        .response(FixedResponse::ok(feature("acme")));
+    mock.mock_get_feature()
+        .add_matcher(MetadataRegexMatcher::new("traceparent".into(), "^00-[0-9a-f]{32}-").unwrap())
+        .expect(1)
+        .response(FixedResponse::ok(feature("traced")));
+    mock.mock_get_feature()
+        .add_matcher(MetadataPrefixMatcher::new(
+            "authorization".into(),
+            "Bearer ".into(),
+        ))
+        .expect(1)
+        .response(FixedResponse::ok(feature("authorized")));
+    mock.mock_get_feature()
+        .add_matcher(MetadataBinEqMatcher::new("token-bin".into(), vec![1, 2, 3]))
+        .expect(1)
+        .response(FixedResponse::ok(feature("binary")));
+    mock.mock_record_route()
+        .add_matcher(MetadataPrefixMatcher::header(
+            "authorization".into(),
+            "Bearer ".into(),
+        ))
+        .expect(1)
+        .response(CountPoints);
+
+    let server = mock.build();
+    server.serve().await;
+    let addr = server.listening_address().await.unwrap();
+    let mut client = RouteGuideClient::connect(addr).await.unwrap();
+
+    let get_feature = |key: &'static str, value: &'static str| {
+        let mut req = Request::new(Point::default());
+        req.metadata_mut().insert(key, value.try_into().unwrap());
+        let mut client = client.clone();
+        async move { client.get_feature(req).await.map(|x| x.into_inner().name) }
+    };
+    assert_eq!(get_feature("tenant", "acme").await.unwrap(), "acme");
+    assert_eq!(
+        get_feature(
+            "traceparent",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"
+        )
+        .await
+        .unwrap(),
+        "traced"
+    );
+    assert_eq!(
+        get_feature("authorization", "Bearer abc").await.unwrap(),
+        "authorized"
+    );
+    assert!(get_feature("authorization", "Basic abc").await.is_err());
+    assert!(get_feature("tenant", "other").await.is_err());
+
+    let mut req = Request::new(Point::default());
+    req.metadata_mut()
+        .insert_bin("token-bin", MetadataValue::from_bytes(&[1, 2, 3]));
+    let response = client.get_feature(req).await.unwrap();
+    assert_eq!(response.into_inner().name, "binary");
+
+    let mut req = Request::new(stream::iter(vec![Point::default()]));
+    req.metadata_mut()
+        .insert("authorization", "Bearer abc".try_into().unwrap());
+    client.record_route(req).await.unwrap();
+
+    let report = server.verify().await;
+    assert_eq!(
+        report
+            .methods
+            .iter()
+            .map(|x| x.mismatches.len())
+            .sum::<usize>(),
+        10,
+        "{}",
+        report
+    );
+    assert!(report.methods.iter().all(|x| x.calls_met()), "{}", report);
+    server.disable_verify_on_drop();
+}
+
+/// Streaming matcher checking all the points are north of the equator
+struct Northern;
+
+impl StreamingMatcher<Point> for Northern {
+    fn single_match(&self, point: &Point) -> bool {
+        point.latitude > 0
+    }
+}
+
+impl MatcherExt for Northern {}
+
+#[tokio::test]
+async fn combined_matchers() {
+    let mut mock = MockRouteGuide::build();
+    let tenant = || {
+        MetadataExistsMatcher::new("x-tenant".into())
+            .or(MetadataExistsMatcher::new("x-org".into()))
+            .and(MetadataExistsMatcher::new("x-debug".into()).not())
+    };
+
+    mock.mock_get_feature()
+        .add_matcher(tenant())
+        .expect(2)
+        .response(FixedResponse::default_ok());
+    mock.mock_record_route()
+        .add_matcher(tenant())
+        .add_matcher(any_of(vec![Northern]).and(not(Northern)).not())
+        .expect(2)
+        .response(CountPoints);
+
+    let server = mock.build();
+    server.serve().await;
+    let addr = server.listening_address().await.unwrap();
+    let mut client = RouteGuideClient::connect(addr).await.unwrap();
+
+    fn with_metadata<T>(mut req: Request<T>, keys: &[&'static str]) -> Request<T> {
+        for key in keys {
+            req.metadata_mut().insert(*key, "".try_into().unwrap());
+        }
+        req
+    }
+    let point = Point {
+        latitude: 10,
+        longitude: 10,
+    };
+
+    for keys in [&["x-tenant"][..], &["x-org"], &["x-tenant", "x-debug"], &[]] {
+        let req = with_metadata(Request::new(point.clone()), keys);
+        let _ = client.get_feature(req).await;
+        let req = with_metadata(Request::new(stream::iter(vec![point.clone()])), keys);
+        let _ = client.record_route(req).await;
+    }
+
+    // Only the requests with x-debug or without a tenant are rejected
+    let report = server.verify().await;
+    for method in &report.methods {
+        assert!(method.calls_met(), "{}", report);
+        assert_eq!(method.mismatches.len(), 2, "{}", report);
+        assert!(method.mismatches[0].matcher.starts_with("all of [any of ["));
+    }
+    server.disable_verify_on_drop();
+}
+
+#[tokio::test]
+async fn metadata_and_message_matchers_combined() {
+    let mut mock = MockRouteGuide::build();
+    mock.mock_record_route()
+        .add_matcher(MetadataExistsMatcher::new("x-tenant".into()).or(Northern))
+        .add_matcher(not(
+            MetadataExistsMatcher::new("x-debug".into()).and(Northern)
+        ))
+        .expect(3)
+        .response(CountPoints);
+
+    let server = mock.build();
+    server.serve().await;
+    let addr = server.listening_address().await.unwrap();
+    let mut client = RouteGuideClient::connect(addr).await.unwrap();
+
+    for (keys, latitude) in [
+        (&["x-tenant"][..], -10),
+        (&[], 10),
+        (&[], -10),
+        (&["x-tenant", "x-debug"], 10),
+        (&["x-tenant", "x-debug"], -10),
+    ] {
+        let point = Point {
+            latitude,
+            longitude: 0,
+        };
+        let mut req = Request::new(stream::iter(vec![point]));
+        for key in keys {
+            req.metadata_mut().insert(*key, "".try_into().unwrap());
+        }
+        let _ = client.record_route(req).await;
+    }
+
+    // Neither a tenant nor a northern point, then debugging with a northern point
+    let report = server.verify().await;
+    let mismatches = &report.methods[0].mismatches;
+    assert_eq!(mismatches.len(), 2, "{}", report);
+    assert!(mismatches[0].matcher.starts_with("any of ["), "{}", report);
+    assert!(
+        mismatches[0].request.contains("latitude: -10"),
+        "{}",
+        report
+    );
+    assert!(
+        mismatches[1].matcher.starts_with("not (all of ["),
+        "{}",
+        report
+    );
+    assert!(mismatches[1].request.contains("x-debug"), "{}", report);
+    server.disable_verify_on_drop();
+}
+
+#[tokio::test]
+async fn metadata_location_and_absence() {
+    let mut mock = MockRouteGuide::build();
+
+    // Unary requests don't have trailers so this is never picked
+    mock.mock_get_feature()
+        .add_matcher(MetadataExistsMatcher::trailer("x-internal".into()))
+        .priority(1)
+        .expect(0)
+        .response(FixedResponse::default_ok());
+    mock.mock_get_feature()
+        .add_matcher(MetadataAbsentMatcher::header("x-internal".into()))
+        .expect(1)
+        .response(FixedResponse::default_ok());
+    mock.mock_route_chat()
+        .add_matcher(MetadataAbsentMatcher::new("x-internal".into()))
+        .expect(2)
+        .response(Conversation::new());
+
+    let server = mock.build();
+    server.serve().await;
+    let addr = server.listening_address().await.unwrap();
+    let mut client = RouteGuideClient::connect(addr).await.unwrap();
+
+    client.get_feature(Point::default()).await.unwrap();
+    let mut req = Request::new(Point::default());
+    req.metadata_mut()
+        .insert("x-internal", "secret".try_into().unwrap());
+    let status = client.get_feature(req).await.unwrap_err();
+    assert_eq!(status.code(), Code::NotFound);
+
+    for internal in [false, true] {
+        let mut req = Request::new(stream::iter(vec![RouteNote::default()]));
+        if internal {
+            req.metadata_mut()
+                .insert("x-internal", "secret".try_into().unwrap());
+        }
+        let mut stream = client.route_chat(req).await.unwrap().into_inner();
+        while stream.message().await.unwrap().is_some() {}
+    }
+
+    let report = server.verify().await;
+    assert!(report.methods.iter().all(|x| x.calls_met()), "{}", report);
+    let mismatches = report
+        .methods
+        .iter()
+        .flat_map(|x| x.mismatches.iter().map(|x| x.matcher.as_str()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        mismatches,
+        [
+            "metadata `x-internal` exists in Trailer",
+            "metadata `x-internal` is absent from Header",
+            "metadata `x-internal` is absent from Any",
+        ],
+        "{}",
+        report
+    );
+    server.disable_verify_on_drop();
+}
+
+#[tokio::test]
+async fn stream_level_matchers() {
+    let mut mock = MockRouteGuide::build();
+    let points = (0..3)
+        .map(|x| Point {
+            latitude: x,
+            longitude: x,
+        })
+        .collect::<Vec<_>>();
+
+    mock.mock_record_route()
+        .add_matcher(MessageCount::new(2..=3))
+        .add_matcher(SequenceEq(points.clone()))
+        .add_matcher(contains(|x: &Point| x.latitude == 1))
+        .add_matcher(ordered_by(|x: &Point| x.latitude))
+        .add_matcher(first_message(|x: &Point| x.latitude == 0))
+        .add_matcher(last_message(|x: &Point| x.latitude == 2))
+        .add_matcher(StreamEnd::clean())
+        .expect(1..=2)
+        .response(CountPoints);
+
+    let server = mock.build();
+    server.serve().await;
+    let addr = server.listening_address().await.unwrap();
+    let mut client = RouteGuideClient::connect(addr).await.unwrap();
+
+    client
+        .record_route(stream::iter(points.clone()))
+        .await
+        .unwrap();
+    let report = server.verify().await;
+    assert!(report.is_success(), "{}", report);
+
+    let reversed = points.into_iter().rev().collect::<Vec<_>>();
+    let status = client
+        .record_route(stream::iter(reversed))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::NotFound);
+    let report = server.verify().await;
+    let mismatches = report.methods[0]
+        .mismatches
+        .iter()
+        .map(|x| x.matcher.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(mismatches.len(), 4, "{}", report);
+    assert!(mismatches.contains(&"messages are ordered by key"));
+    assert!(mismatches.contains(&"first message matches predicate"));
+    assert!(mismatches.contains(&"last message matches predicate"));
+    server.disable_verify_on_drop();
+}
+
+#[tokio::test]
+async fn long_streams_are_matched_without_lagging() {
+    let mut mock = MockRouteGuide::build();
+    let points = (0..5000)
+        .map(|x| Point {
+            latitude: x,
+            longitude: x,
+        })
+        .collect::<Vec<_>>();
+    let notes = (0..5000)
+        .map(|x| RouteNote {
+            location: None,
+            message: x.to_string(),
+        })
+        .collect::<Vec<_>>();
+
+    mock.mock_record_route()
+        .add_matcher(MessageCount::new(5000))
+        .add_matcher(SequenceEq(points.clone()))
+        .add_matcher(ordered_by(|x: &Point| x.latitude))
+        .add_matcher(Northern.not())
+        .expect(1)
+        .response(CountPoints);
+    mock.mock_route_chat()
+        .add_matcher(MessageCount::new(5000))
+        .add_matcher(SequenceEq(notes.clone()))
+        .expect(1)
+        .response(Conversation::new());
+
+    let server = mock.build();
+    server.serve().await;
+    let addr = server.listening_address().await.unwrap();
+    let mut client = RouteGuideClient::connect(addr).await.unwrap();
+
+    let summary = client.record_route(stream::iter(points)).await.unwrap();
+    assert_eq!(summary.into_inner().point_count, 5000);
+    let mut replies = client
+        .route_chat(stream::iter(notes))
+        .await
+        .unwrap()
+        .into_inner();
+    while replies.message().await.unwrap().is_some() {}
+
+    let report = server.verify().await;
+    assert!(report.is_success(), "{}", report);
+}
+
+#[tokio::test]
+async fn bidirectional_streams_are_checked_when_the_responder_ignores_them() {
+    let mut mock = MockRouteGuide::build();
+
+    // Unimplemented fails the call without reading the stream
+    mock.mock_route_chat()
+        .add_matcher(MetadataExistsMatcher::new("x-required".into()))
+        .add_matcher(MessageCount::new(3))
+        .expect(1);
+
+    let server = mock.build();
+    server.serve().await;
+    let addr = server.listening_address().await.unwrap();
+    let mut client = RouteGuideClient::connect(addr).await.unwrap();
+
+    let notes = vec![RouteNote::default(), RouteNote::default()];
+    let status = client.route_chat(stream::iter(notes)).await.unwrap_err();
+    assert_eq!(status.code(), Code::Unimplemented);
+
+    // The headers are checked as soon as the call comes in
+    let report = server.verify().await;
+    assert!(!report.is_success(), "{}", report);
+
+    // The messages are still read and checked after the call has failed
+    let recorded = async {
+        loop {
+            let requests = server.route_chat_requests().await;
+            if !requests.is_empty() {
+                return requests;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    let requests = tokio::time::timeout(Duration::from_secs(5), recorded)
+        .await
+        .expect("stream wasn't recorded");
+    assert_eq!(requests[0].messages.len(), 2);
+
+    let report = server.verify().await;
+    let mismatches = &report.methods[0].mismatches;
+    assert_eq!(mismatches.len(), 2, "{}", report);
+    assert!(mismatches[0].matcher.contains("x-required"), "{}", report);
+    server.disable_verify_on_drop();
+}
+
+#[tokio::test]
+async fn bidirectional_streams_check_trailers_at_the_end() {
+    let mut mock = MockRouteGuide::build();
+
+    mock.mock_route_chat()
+        .add_matcher(MetadataExistsMatcher::new("x-checksum".into()))
+        .add_matcher(MetadataExistsMatcher::trailer("x-checksum".into()))
+        .add_matcher(MetadataAbsentMatcher::header("x-checksum".into()))
+        .expect(1)
+        .response(Conversation::new());
+
+    let server = mock.build();
+    server.serve().await;
+    let addr = server.listening_address().await.unwrap();
+
+    let mut trailers = http::HeaderMap::new();
+    trailers.insert("x-checksum", "abc".parse().unwrap());
+    send_raw(
+        &addr,
+        "RouteChat",
+        &RouteNote::default(),
+        RawEnd::Trailers(trailers),
+    )
+    .await;
+
+    // The stream is checked before the server finishes its side
+    let report = server.verify().await;
+    assert!(report.is_success(), "{}", report);
+    let requests = server.route_chat_requests().await;
+    let trailers = requests[0].trailers.as_ref().unwrap();
+    assert_eq!(trailers.get("x-checksum").unwrap(), "abc");
+}
+
+#[tokio::test]
+async fn stream_errors_reach_matchers_and_responders() {
+    let mut mock = MockRouteGuide::build();
+
+    mock.mock_record_route()
+        .add_matcher(StreamEnd::errored_with(Code::Internal))
+        .expect(1)
+        .response(RejectErrors);
+
+    let server = mock.build();
+    server.serve().await;
+    let addr = server.listening_address().await.unwrap();
+
+    // Send route notes to a method expecting points, these can't be decoded so the server sees
+    // the stream fail
+    let channel = tonic::transport::Endpoint::from_shared(addr)
+        .unwrap()
+        .connect()
+        .await
+        .unwrap();
+    let mut client = tonic::client::Grpc::new(channel);
+    client.ready().await.unwrap();
+    let notes = vec![RouteNote {
+        location: Some(Point {
+            latitude: 1,
+            longitude: 1,
+        }),
+        message: "not a point".to_string(),
+    }];
+    let status = client
+        .client_streaming(
+            Request::new(stream::iter(notes)),
+            "/routeguide.RouteGuide/RecordRoute".parse().unwrap(),
+            tonic::codec::ProstCodec::<RouteNote, RouteSummary>::default(),
+        )
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument, "{:?}", status);
+
+    let report = server.verify().await;
+    assert!(report.is_success(), "{}", report);
+}
+
+/// How `send_raw` ends the request stream.
+enum RawEnd {
+    /// Half-close the stream with trailing metadata, which a tonic client can't send
+    Trailers(http::HeaderMap),
+    /// Cancel the call, the way a client does when the call is dropped or times out. A tonic
+    /// client only resets the stream once the request stream is finished.
+    Cancel,
+}
+
+/// Send a message to a streaming method then end the stream, talking HTTP/2 directly. When the
+/// stream is half-closed this waits for the whole response.
+async fn send_raw(addr: &str, method: &str, message: &impl prost::Message, end: RawEnd) {
+    let authority = addr.trim_start_matches("http://");
+    let tcp = tokio::net::TcpStream::connect(authority).await.unwrap();
+    let (client, mut connection) = h2::client::handshake(tcp).await.unwrap();
+    let mut ping_pong = connection.ping_pong().unwrap();
+    tokio::spawn(connection);
+    let mut client = client.ready().await.unwrap();
+
+    let request = http::Request::post(format!("{}/routeguide.RouteGuide/{}", addr, method))
+        .header("content-type", "application/grpc")
+        .header("te", "trailers")
+        .body(())
+        .unwrap();
+    let (response, mut stream) = client.send_request(request, false).unwrap();
+    // A gRPC message is an uncompressed flag and the length before the encoded message
+    let message = message.encode_to_vec();
+    let mut frame = vec![0];
+    frame.extend((message.len() as u32).to_be_bytes());
+    frame.extend(message);
+    stream.send_data(frame.into(), false).unwrap();
+    match end {
+        RawEnd::Trailers(trailers) => {
+            stream.send_trailers(trailers).unwrap();
+            let mut body = response.await.unwrap().into_body();
+            while let Some(data) = body.data().await {
+                data.unwrap();
+            }
+            let _ = body.trailers().await;
+        }
+        RawEnd::Cancel => {
+            // h2 drops whatever hasn't been sent yet on a reset, wait for a ping to make it to
+            // the server so the reset comes after the request
+            ping_pong.ping(h2::Ping::opaque()).await.unwrap();
+            stream.send_reset(h2::Reason::CANCEL);
+            let _ = response.await;
+        }
+    }
+}
+
+#[tokio::test]
+async fn cancelled_streams_reach_matchers_and_are_recorded() {
+    let mut mock = MockRouteGuide::build();
+
+    mock.mock_record_route()
+        .add_matcher(MessageCount::new(1))
+        .add_matcher(StreamEnd::errored_with(Code::Cancelled))
+        .expect(1)
+        // Still responding when the reset arrives, which is when hyper drops the handler
+        .response(Delayed::fixed(CountPoints, Duration::from_millis(200)));
+
+    let server = mock.build();
+    server.serve().await;
+    let addr = server.listening_address().await.unwrap();
+    send_raw(&addr, "RecordRoute", &Point::default(), RawEnd::Cancel).await;
+
+    // The server carries on with the stream after the client has gone
+    let recorded = async {
+        loop {
+            let requests = server.record_route_requests().await;
+            if !requests.is_empty() {
+                return requests;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    let requests = tokio::time::timeout(Duration::from_secs(5), recorded)
+        .await
+        .expect("cancelled stream wasn't recorded");
+    assert_eq!(requests[0].messages, vec![Point::default()]);
+    let error = requests[0].error.as_ref().unwrap();
+    assert_eq!(error.code(), Code::Cancelled);
+
+    let report = server.verify().await;
+    assert!(report.is_success(), "{}", report);
+}
+
+#[tokio::test]
+async fn received_requests_are_recorded() {
+    let mut mock = MockRouteGuide::build();
+    let points = (0..3)
+        .map(|x| Point {
+            latitude: x,
+            longitude: x,
+        })
+        .collect::<Vec<_>>();
+
+    mock.mock_get_feature()
+        .add_matcher(MetadataExistsMatcher::new("x-known".into()))
+        .response(FixedResponse::default_ok());
+    mock.mock_record_route().response(CountPoints);
+
+    let server = mock.build();
+    server.serve().await;
+    let addr = server.listening_address().await.unwrap();
+    let mut client = RouteGuideClient::connect(addr).await.unwrap();
+
+    let mut req = Request::new(Point {
+        latitude: 28,
+        longitude: 87,
+    });
+    req.metadata_mut()
+        .insert("x-known", MetadataValue::from_static("yes"));
+    client.get_feature(req).await.unwrap();
+    client
+        .record_route(stream::iter(points.clone()))
+        .await
+        .unwrap();
+    // Requests no mock matched are still recorded
+    client.get_feature(Point::default()).await.unwrap_err();
+
+    let features = server.get_feature_requests().await;
+    assert_eq!(features.len(), 2);
+    assert_eq!(
+        features[0].message(),
+        Some(&Point {
+            latitude: 28,
+            longitude: 87,
+        })
+    );
+    assert_eq!(features[0].metadata.get("x-known").unwrap(), "yes");
+    assert!(features[0].remote_addr.is_some());
+    assert_eq!(features[1].message(), Some(&Point::default()));
+
+    let routes = server.record_route_requests().await;
+    assert_eq!(routes.len(), 1);
+    assert_eq!(routes[0].messages, points);
+    assert!(routes[0].error.is_none());
+
+    let calls = server.received_requests().await;
+    let methods = calls.iter().map(|x| x.method.as_str()).collect::<Vec<_>>();
+    assert_eq!(methods, ["get_feature", "record_route", "get_feature"]);
+    assert_eq!(calls[1].request.messages.len(), 3);
+
+    server.reset().await;
+    assert!(server.received_requests().await.is_empty());
+    assert!(server.list_features_requests().await.is_empty());
+}
+
+#[tokio::test]
+async fn reset_does_not_wait_for_calls_in_flight() {
+    let mut mock = MockRouteGuide::build();
+    let (entered_tx, mut entered_rx) = tokio::sync::mpsc::unbounded_channel();
+    mock.mock_get_feature()
+        .async_response(move |_req: Request<Point>| {
+            let _ = entered_tx.send(());
+            futures::future::pending()
+        });
+
+    let server = mock.build();
+    server.serve().await;
+    let addr = server.listening_address().await.unwrap();
+    let mut client = RouteGuideClient::connect(addr).await.unwrap();
+
+    let call = tokio::spawn(async move { client.get_feature(Point::default()).await });
+    entered_rx.recv().await.unwrap();
+
+    tokio::time::timeout(Duration::from_secs(5), server.reset())
+        .await
+        .expect("reset waited for the call in flight");
+    assert!(server.get_feature_requests().await.is_empty());
+    call.abort();
+}
+
+/// Async responder naming the feature at a point
+struct LookUp;
+
+#[tonic::async_trait]
+impl AsyncResponder<Point, Feature> for LookUp {
+    async fn respond(&self, request: Request<Point>) -> Result<Response<Feature>, Status> {
+        tokio::task::yield_now().await;
+        Ok(Response::new(Feature {
+            name: "North Pole".to_string(),
+            location: Some(request.into_inner()),
+        }))
+    }
+}
+
+#[tokio::test]
+async fn closure_responders() {
+    let mut mock = MockRouteGuide::build();
+
+    mock.mock_get_feature()
+        .add_matcher(predicate(|point: &Point| point.latitude >= 0))
+        .response(ResponseFn::new(|req: &Request<Point>| {
+            Ok(Response::new(Feature {
+                name: format!("feature at {}", req.get_ref().latitude),
+                location: Some(req.get_ref().clone()),
+            }))
+        }));
+    mock.mock_get_feature()
+        .async_response(|req: Request<Point>| async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            Err(Status::out_of_range(format!(
+                "latitude {} is too far south",
+                req.get_ref().latitude
+            )))
+        });
+    mock.mock_get_feature()
+        .add_matcher(predicate(|point: &Point| point.latitude == 90))
+        .priority(1)
+        .response(Delayed::fixed(LookUp, Duration::from_millis(10)));
+
+    let server = mock.build();
+    server.serve().await;
+    let addr = server.listening_address().await.unwrap();
+    let mut client = RouteGuideClient::connect(addr).await.unwrap();
+
+    let point = Point {
+        latitude: 28,
+        longitude: 87,
+    };
+    let feature = client
+        .get_feature(point.clone())
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(feature.name, "feature at 28");
+    assert_eq!(feature.location, Some(point));
+
+    let status = client
+        .get_feature(Point {
+            latitude: -10,
+            longitude: 0,
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::OutOfRange);
+    assert_eq!(status.message(), "latitude -10 is too far south");
+
+    let feature = client
+        .get_feature(Point {
+            latitude: 90,
+            longitude: 0,
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(feature.name, "North Pole");
+}
+
+#[tokio::test]
+async fn sequenced_responses() {
+    let mut mock = MockRouteGuide::build();
+    let feature = |name: &str| Feature {
+        name: name.to_string(),
+        location: None,
+    };
+
+    mock.mock_get_feature()
+        .add_matcher(predicate(|point: &Point| point.latitude == 0))
+        .expect(4)
+        .response(
+            SequenceResponder::new()
+                .err(Status::unavailable("try again"))
+                .err(Status::unavailable("try again"))
+                .ok(feature("found")),
+        );
+    mock.mock_get_feature()
+        .add_matcher(predicate(|point: &Point| point.latitude == 1))
+        .response(
+            SequenceResponder::new()
+                .ok(feature("first"))
+                .ok(feature("second"))
+                .when_exhausted(Exhausted::Cycle),
+        );
+    mock.mock_get_feature()
+        .add_matcher(predicate(|point: &Point| point.latitude == 2))
+        .response(
+            SequenceResponder::new()
+                .ok(feature("only"))
+                .when_exhausted(Exhausted::Fail(Status::resource_exhausted("no more"))),
+        );
+
+    let server = mock.build();
+    server.serve().await;
+    let addr = server.listening_address().await.unwrap();
+    let mut client = RouteGuideClient::connect(addr).await.unwrap();
+
+    let point = |latitude| Point {
+        latitude,
+        longitude: 0,
+    };
+    let mut results = vec![];
+    for latitude in [0, 0, 0, 0, 1, 1, 1, 2, 2] {
+        let result = client.get_feature(point(latitude)).await;
+        results.push(match result {
+            Ok(response) => response.into_inner().name,
+            Err(status) => format!("{:?}", status.code()),
+        });
+    }
+    assert_eq!(
+        results,
+        [
+            "Unavailable",
+            "Unavailable",
+            "found",
+            "found",
+            "first",
+            "second",
+            "first",
+            "only",
+            "ResourceExhausted"
+        ]
+    );
+
+    let report = server.verify().await;
+    assert!(report.is_success(), "{}", report);
+}
+
+#[tokio::test(start_paused = true)]
+async fn delayed_responses() {
+    use tokio::time::Instant;
+
+    let mut mock = MockRouteGuide::build();
+    let feature = |name: &str| Feature {
+        name: name.to_string(),
+        location: None,
+    };
+    let latitude = |latitude| move |point: &Point| point.latitude == latitude;
+    let uniform = || {
+        Delayed::uniform(
+            FixedResponse::ok(feature("random")),
+            Duration::from_millis(20),
+            Duration::from_millis(40),
+            7,
+        )
+    };
+    let features = || {
+        StreamResponse::default()
+            .message(feature("a"))
+            .message(feature("b"))
+            .message(feature("c"))
+    };
+
+    // The last delay is repeated once the sequence runs out
+    mock.mock_get_feature()
+        .add_matcher(predicate(latitude(0)))
+        .response(Delayed::sequence(
+            FixedResponse::ok(feature("slow")),
+            vec![Duration::from_millis(500), Duration::from_millis(100)],
+        ));
+    mock.mock_get_feature()
+        .add_matcher(predicate(latitude(1)))
+        .response(uniform());
+    mock.mock_get_feature()
+        .add_matcher(predicate(latitude(2)))
+        .response(uniform());
+    mock.mock_list_features()
+        .add_matcher(predicate(|x: &Rectangle| x.lo.is_none()))
+        .response(Delayed::fixed(features(), Duration::from_millis(30)));
+    mock.mock_list_features()
+        .add_matcher(predicate(|x: &Rectangle| x.lo.is_some()))
+        .response(Delayed::fixed(features(), Duration::from_millis(30)).per_message());
+    mock.mock_record_route()
+        .response(Delayed::fixed(CountPoints, Duration::from_millis(200)));
+
+    let server = mock.build();
+    server.serve().await;
+    let addr = server.listening_address().await.unwrap();
+    let mut client = RouteGuideClient::connect(addr).await.unwrap();
+
+    // The clock is paused, it only jumps ahead to the next timer when everything is waiting. The
+    // calls themselves take no time, so the times are exactly the delays.
+    let get_feature_delays = |latitude| {
+        let mut client = client.clone();
+        async move {
+            let mut delays = vec![];
+            for _ in 0..3 {
+                let start = Instant::now();
+                let point = Point {
+                    latitude,
+                    longitude: 0,
+                };
+                client.get_feature(point).await.unwrap();
+                delays.push(start.elapsed().as_millis());
+            }
+            delays
+        }
+    };
+    assert_eq!(get_feature_delays(0).await, [500, 100, 100]);
+    let delays = get_feature_delays(1).await;
+    assert!(delays.iter().all(|x| (20..=40).contains(x)), "{:?}", delays);
+    // The same seed gives the same delays
+    assert_eq!(get_feature_delays(2).await, delays);
+
+    for (rectangle, times) in [
+        (Rectangle::default(), [30, 30, 30]),
+        (
+            Rectangle {
+                lo: Some(Point::default()),
+                hi: None,
+            },
+            [30, 60, 90],
+        ),
+    ] {
+        let start = Instant::now();
+        let mut stream = client.list_features(rectangle).await.unwrap().into_inner();
+        let mut sent = vec![];
+        while stream.message().await.unwrap().is_some() {
+            sent.push(start.elapsed().as_millis());
+        }
+        assert_eq!(sent, times);
+    }
+
+    let start = Instant::now();
+    let points = stream::iter(vec![Point::default(), Point::default()]);
+    let summary = client.record_route(points).await.unwrap().into_inner();
+    assert_eq!(summary.point_count, 2);
+    assert_eq!(start.elapsed(), Duration::from_millis(200));
+}
+
+#[tokio::test]
+async fn deadlines_are_matched_and_enforced() {
+    let mut mock = MockRouteGuide::build();
+    let feature = |name: &str| Feature {
+        name: name.to_string(),
+        location: None,
+    };
+
+    mock.mock_get_feature()
+        .add_matcher(DeadlineMatcher::new(
+            Duration::from_millis(50)..=Duration::from_secs(1),
+        ))
+        .expect(1)
+        .response(Delayed::fixed(
+            FixedResponse::ok(feature("slow")),
+            Duration::from_secs(10),
+        ));
+    mock.mock_list_features()
+        .add_matcher(DeadlineMatcher::any())
+        .expect(1)
+        .response(
+            StreamResponse::default()
+                .message(feature("a"))
+                .message(feature("b"))
+                .delayed_message(feature("c"), Duration::from_secs(60)),
+        );
+    mock.enforce_deadlines(true);
+
+    let server = mock.build();
+    server.serve().await;
+    let addr = server.listening_address().await.unwrap();
+    let mut client = client_without_deadlines(&addr);
+
+    let mut req = Request::new(Point::default());
+    req.set_timeout(Duration::from_millis(100));
+    let start = tokio::time::Instant::now();
+    let status = client.get_feature(req).await.unwrap_err();
+    assert_eq!(status.code(), Code::DeadlineExceeded, "{:?}", status);
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    // The deadline covers the whole stream, not just the response headers
+    let mut req = Request::new(Rectangle::default());
+    req.set_timeout(Duration::from_secs(1));
+    let mut stream = client.list_features(req).await.unwrap().into_inner();
+    let mut names = vec![];
+    let status = loop {
+        match stream.message().await {
+            Ok(Some(feature)) => names.push(feature.name),
+            Ok(None) => panic!("stream finished before the deadline"),
+            Err(status) => break status,
+        }
+    };
+    assert_eq!(status.code(), Code::DeadlineExceeded);
+    assert_eq!(names, ["a", "b"]);
+
+    let report = server.verify().await;
+    assert!(report.is_success(), "{}", report);
+    let requests = server.get_feature_requests().await;
+    assert_eq!(requests[0].timeout(), Some(Duration::from_millis(100)));
+}
+
+#[tokio::test]
+async fn deadlines_are_not_enforced_by_default() {
+    let mut mock = MockRouteGuide::build();
+
+    mock.mock_get_feature().response(Delayed::fixed(
+        FixedResponse::default_ok(),
+        Duration::from_millis(100),
+    ));
+    mock.mock_record_route()
+        .add_matcher(DeadlineMatcher::none())
+        .response(CountPoints);
+
+    let server = mock.build();
+    server.serve().await;
+    let addr = server.listening_address().await.unwrap();
+    let mut client = client_without_deadlines(&addr);
+
+    let mut req = Request::new(Point::default());
+    req.set_timeout(Duration::from_millis(10));
+    client.get_feature(req).await.unwrap();
+
+    client
+        .record_route(stream::iter(vec![Point::default()]))
+        .await
+        .unwrap();
+    let mut req = Request::new(stream::iter(vec![Point::default()]));
+    req.set_timeout(Duration::from_secs(1));
+    let status = client.record_route(req).await.unwrap_err();
+    assert_eq!(status.code(), Code::NotFound);
+
+    let report = server.verify().await;
+    let record_route = report.methods.iter().find(|x| x.method == "record_route");
+    let mismatches = &record_route.unwrap().mismatches;
+    assert_eq!(mismatches.len(), 1, "{}", report);
+    assert_eq!(mismatches[0].matcher, "no deadline");
+    server.disable_verify_on_drop();
+}
+
+#[tokio::test]
+async fn faults_are_injected() {
+    let mut mock = MockRouteGuide::build();
+    let feature = |name: &str| Feature {
+        name: name.to_string(),
+        location: None,
+    };
+    let point = |latitude| Point {
+        latitude,
+        longitude: 0,
+    };
+    let rectangle = |latitude| Rectangle {
+        lo: Some(point(latitude)),
+        hi: None,
+    };
+
+    mock.mock_get_feature()
+        .add_matcher(predicate(|point: &Point| point.latitude == 1))
+        .response(FaultResponse::new(Fault::Reset(Reason::REFUSED_STREAM)));
+    mock.mock_get_feature()
+        .add_matcher(predicate(|point: &Point| point.latitude == 2))
+        .response(FaultResponse::new(Fault::GoAway(Reason::NO_ERROR)));
+    mock.mock_get_feature()
+        .add_matcher(predicate(|point: &Point| point.latitude == 3))
+        .response(FaultResponse::new(Fault::CloseConnection));
+    mock.mock_get_feature()
+        .response(FixedResponse::ok(feature("fine")));
+    mock.mock_list_features()
+        .add_matcher(predicate(move |rect: &Rectangle| rect.lo == Some(point(1))))
+        .response(FaultResponse::after(
+            vec![feature("a"), feature("b")],
+            Fault::Abort,
+        ));
+    mock.mock_list_features()
+        .add_matcher(predicate(move |rect: &Rectangle| rect.lo == Some(point(2))))
+        .response(FaultResponse::after(
+            vec![feature("a")],
+            Fault::Reset(Reason::CANCEL),
+        ));
+
+    let server = mock.build();
+    server.serve().await;
+    let addr = server.listening_address().await.unwrap();
+    let mut client = RouteGuideClient::connect(addr).await.unwrap();
+
+    // A reset before the response headers
+    let status = client.get_feature(point(1)).await.unwrap_err();
+    assert_eq!(status.code(), Code::Unavailable);
+    assert!(status.message().contains("refused stream"), "{:?}", status);
+
+    // GOAWAY and closing the connection make the client reconnect for the next call
+    client.get_feature(point(2)).await.unwrap_err();
+    client.get_feature(point(0)).await.unwrap();
+    let status = client.get_feature(point(3)).await.unwrap_err();
+    assert_eq!(status.code(), Code::Unknown, "{:?}", status);
+    client.get_feature(point(0)).await.unwrap();
+    let addrs = server
+        .get_feature_requests()
+        .await
+        .into_iter()
+        .map(|x| x.remote_addr.unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(addrs.len(), 5);
+    assert_eq!(addrs[0], addrs[1]);
+    assert_ne!(addrs[1], addrs[2]);
+    assert_eq!(addrs[2], addrs[3]);
+    assert_ne!(addrs[3], addrs[4]);
+
+    // Stopping a stream without trailers, the client never gets a status
+    let mut stream = client
+        .list_features(rectangle(1))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(stream.message().await.unwrap().unwrap().name, "a");
+    assert_eq!(stream.message().await.unwrap().unwrap().name, "b");
+    assert!(stream.message().await.unwrap().is_none());
+    assert!(stream.trailers().await.unwrap().is_none());
+
+    // A reset after the first message
+    let mut stream = client
+        .list_features(rectangle(2))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(stream.message().await.unwrap().unwrap().name, "a");
+    let status = stream.message().await.unwrap_err();
+    assert_eq!(status.code(), Code::Cancelled, "{:?}", status);
+}
+
+#[tokio::test]
+async fn chaos_is_reproducible() {
+    async fn run(seed: u64) -> Vec<Result<String, Code>> {
+        let mut mock = MockRouteGuide::build();
+        mock.mock_get_feature().response(
+            Chaos::new(FixedResponse::ok(Feature::default()), seed)
+                .latency(0.2, Duration::from_millis(5))
+                .error(0.3, Status::aborted("flaky"))
+                .fault(0.1, Fault::Reset(Reason::REFUSED_STREAM)),
+        );
+
+        let server = mock.build();
+        server.serve().await;
+        let addr = server.listening_address().await.unwrap();
+        let mut client = RouteGuideClient::connect(addr).await.unwrap();
+
+        let mut outcomes = vec![];
+        for _ in 0..50 {
+            let response = client.get_feature(Point::default()).await;
+            outcomes.push(response.map(|x| x.into_inner().name).map_err(|x| x.code()));
+        }
+        outcomes
+    }
+
+    let outcomes = run(7).await;
+    assert_eq!(outcomes, run(7).await);
+    assert_ne!(outcomes, run(8).await);
+    for expected in [
+        Ok(String::new()),
+        Err(Code::Aborted),
+        Err(Code::Unavailable),
+    ] {
+        assert!(outcomes.contains(&expected), "{:?}", outcomes);
+    }
+}
+
+#[tokio::test]
+async fn fixed_responses_with_metadata() {
+    let mut mock = MockRouteGuide::build();
+
+    mock.mock_get_feature().response(
+        FixedResponse::default_ok()
+            .with_header("x-request-id", "abc123")
+            .with_trailer("x-ratelimit-remaining", "0"),
+    );
+    mock.mock_record_route().response(
+        FixedResponse::err(Status::resource_exhausted("slow down"))
+            .with_header("retry-after", "1")
+            .with_trailer("x-ratelimit-remaining", "0")
+            .with_status_details(&b"quota"[..]),
+    );
+
+    let server = mock.build();
+    server.serve().await;
+    let addr = server.listening_address().await.unwrap();
+    let mut client = RouteGuideClient::connect(addr).await.unwrap();
+
+    // The client merges the trailers into the response metadata
+    let response = client.get_feature(Point::default()).await.unwrap();
+    assert_eq!(response.metadata().get("x-request-id").unwrap(), "abc123");
+    assert_eq!(
+        response.metadata().get("x-ratelimit-remaining").unwrap(),
+        "0"
+    );
 
-    assert!(!server.verify().await);
+    let status = client
+        .record_route(stream::iter(vec![Point::default()]))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::ResourceExhausted);
+    assert_eq!(status.message(), "slow down");
+    assert_eq!(status.details(), b"quota");
+    assert_eq!(status.metadata().get("retry-after").unwrap(), "1");
+    assert_eq!(status.metadata().get("x-ratelimit-remaining").unwrap(), "0");
 }
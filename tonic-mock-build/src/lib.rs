@@ -0,0 +1,126 @@
+//! Build script helper to generate mock services with tonic-build.
+//!
+//! ```no_run
+//! fn main() -> Result<(), Box<dyn std::error::Error>> {
+//!     tonic_mock_build::compile_protos(
+//!         tonic_mock_build::tonic_build::configure(),
+//!         &["protos/routeguide/route_guide.proto"],
+//!         &["protos"],
+//!     )?;
+//!     Ok(())
+//! }
+//! ```
+use prost::Message;
+use prost_types::FileDescriptorSet;
+use std::collections::BTreeSet;
+use std::env;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// Reexport so users can build their config with the same tonic-build version we use.
+pub use tonic_build;
+
+/// The attribute added to every server module with a service in it.
+pub const MOCK_ATTRIBUTE: &str = "#[tonic_mock::mock]";
+
+/// Compile the protos like `tonic_build::Builder::compile` but with the `#[tonic_mock::mock]`
+/// attribute applied to the server module of every package which contains a service.
+///
+/// To find the services protoc is ran once up front and the resulting file descriptor set is
+/// reused by tonic-build, this means any `file_descriptor_set_path` set on the builder is
+/// overwritten.
+pub fn compile_protos(
+    builder: tonic_build::Builder,
+    protos: &[impl AsRef<Path>],
+    includes: &[impl AsRef<Path>],
+) -> Result<()> {
+    let out_dir = env::var_os("OUT_DIR")
+        .map(PathBuf::from)
+        .ok_or_else(|| Error::other("OUT_DIR environment variable is not set"))?;
+    let descriptor_path = out_dir.join("tonic_mock_descriptor_set.bin");
+
+    let descriptors = run_protoc(&descriptor_path, protos, includes)?;
+
+    let mut builder = builder
+        .file_descriptor_set_path(&descriptor_path)
+        .skip_protoc_run();
+    for pattern in module_patterns(&service_packages(&descriptors)) {
+        builder = builder.server_mod_attribute(pattern, MOCK_ATTRIBUTE);
+    }
+    builder.compile(protos, includes)
+}
+
+/// The patterns to give tonic-build so every package gets the attribute exactly once.
+///
+/// tonic-build matches a module pattern against the end of the package name, so `b` also
+/// matches `a.b`. A pattern with a leading dot is matched from the start, but tonic-build passes
+/// the package without one so those never match. Instead a package is left out when a shorter
+/// package at the end of its name already covers it.
+fn module_patterns(packages: &BTreeSet<String>) -> Vec<&String> {
+    packages
+        .iter()
+        .filter(|package| {
+            !packages.iter().any(|other| {
+                package
+                    .strip_suffix(other.as_str())
+                    .is_some_and(|prefix| prefix.ends_with('.'))
+            })
+        })
+        .collect()
+}
+
+/// Get all the packages which define at least one service. Services without a package can't be
+/// matched by tonic-build's module attributes so are skipped.
+pub fn service_packages(descriptors: &FileDescriptorSet) -> BTreeSet<String> {
+    descriptors
+        .file
+        .iter()
+        .filter(|file| !file.service.is_empty())
+        .filter_map(|file| file.package.clone())
+        .filter(|package| !package.is_empty())
+        .collect()
+}
+
+/// Invoke protoc the same way prost-build does so the descriptor set can be shared with it.
+fn run_protoc(
+    descriptor_path: &Path,
+    protos: &[impl AsRef<Path>],
+    includes: &[impl AsRef<Path>],
+) -> Result<FileDescriptorSet> {
+    let protoc = prost_build::protoc_from_env();
+
+    let mut cmd = Command::new(&protoc);
+    cmd.arg("--include_imports")
+        .arg("--include_source_info")
+        .arg("-o")
+        .arg(descriptor_path);
+
+    for include in includes {
+        if include.as_ref().exists() {
+            cmd.arg("-I").arg(include.as_ref());
+        }
+    }
+    if let Some(protoc_include) = prost_build::protoc_include_from_env() {
+        cmd.arg("-I").arg(protoc_include);
+    }
+    for proto in protos {
+        cmd.arg(proto.as_ref());
+    }
+
+    let output = cmd.output().map_err(|e| {
+        Error::new(
+            e.kind(),
+            format!("failed to invoke protoc (path: {:?}): {}", protoc, e),
+        )
+    })?;
+    if !output.status.success() {
+        return Err(Error::other(format!(
+            "protoc failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        )));
+    }
+
+    let bytes = std::fs::read(descriptor_path)?;
+    FileDescriptorSet::decode(bytes.as_slice()).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
@@ -1,97 +1,471 @@
 use proc_macro::TokenStream;
-use quote::quote;
-use syn::{token::Token, fold::Fold, parse_macro_input, Ident, Item, ItemMod, ItemTrait, TraitItem, TraitItemFn, ImplItemFn};
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
 use std::collections::BTreeMap;
+use syn::{
+    parse_macro_input, parse_quote, FnArg, GenericArgument, Ident, Item, ItemMod, ItemTrait,
+    PathArguments, ReturnType, TraitItem, TraitItemFn, Type, TypeParamBound,
+};
 
+/// The shape of a gRPC method, worked out from the signature tonic-build generates for it.
+enum MethodKind {
+    Unary,
+    ClientStream,
+    ServerStream,
+    BidirStream,
+}
 
+/// Everything needed to generate the mock for a single method in the service trait.
 struct MethodMockGen {
-    /// The trait item function to implement for the mock server
-    trait_fn: TokenStream,
-    builder_method: TokenStream,
-    mock_type: (String, String),
-    
+    name: Ident,
+    kind: MethodKind,
+    /// The message type sent by the client
+    request: Type,
+    /// The message type sent by the server
+    response: Type,
+    /// For server streaming methods, the associated type used for the response stream
+    stream_type: Option<Ident>,
 }
 
-#[proc_macro_attribute]
-pub fn mock(args: TokenStream, item: TokenStream) -> TokenStream {
-    let mut input = parse_macro_input!(item as ItemMod);
+impl MethodMockGen {
+    fn from_trait_fn(item: &TraitItemFn, streams: &BTreeMap<Ident, Type>) -> Option<Self> {
+        let request = item.sig.inputs.iter().find_map(|x| match x {
+            FnArg::Typed(arg) => generic_arg(&arg.ty, "Request"),
+            FnArg::Receiver(_) => None,
+        })?;
+        let (request, client_stream) = match generic_arg(request, "Streaming") {
+            Some(inner) => (inner, true),
+            None => (request, false),
+        };
+
+        let ReturnType::Type(_, output) = &item.sig.output else {
+            return None;
+        };
+        let response = generic_arg(generic_arg(output, "Result")?, "Response")?;
+        let (response, stream_type) = match associated_type(response) {
+            Some(ident) => (streams.get(&ident)?, Some(ident)),
+            None => (response, None),
+        };
+
+        let kind = match (client_stream, stream_type.is_some()) {
+            (false, false) => MethodKind::Unary,
+            (true, false) => MethodKind::ClientStream,
+            (false, true) => MethodKind::ServerStream,
+            (true, true) => MethodKind::BidirStream,
+        };
+
+        Some(Self {
+            name: item.sig.ident.clone(),
+            kind,
+            request: request.clone(),
+            response: response.clone(),
+            stream_type,
+        })
+    }
+
+    fn field_name(&self) -> Ident {
+        format_ident!("{}_mock", self.name)
+    }
+
+    /// The type to store the mocks for this method.
+    fn field_type(&self) -> Type {
+        let mock_type = self.mock_type();
+        parse_quote! { tonic_mock::codegen::MethodMocks<#mock_type> }
+    }
+
+    /// Docs for the builder method adding a mock for this method.
+    fn mock_doc(&self) -> &'static str {
+        match self.kind {
+            MethodKind::BidirStream => {
+                " Add a mock for the method. The responder starts before the messages arrive, so \
+                 requests go to the first mock whose matchers on the headers pass. Matchers on \
+                 the messages or trailers are checked once the stream ends and fail \
+                 verification if they don't pass."
+            }
+            _ => " Add a mock for the method, requests go to the first mock whose matchers pass.",
+        }
+    }
+
+    /// The method mock type for this method.
+    fn mock_type(&self) -> Type {
+        let request = &self.request;
+        let response = &self.response;
+        match self.kind {
+            MethodKind::Unary => parse_quote! {
+                tonic_mock::codegen::UnaryMethodMock<#request, #response>
+            },
+            MethodKind::ClientStream => parse_quote! {
+                tonic_mock::codegen::ClientStreamMethodMock<#request, #response>
+            },
+            MethodKind::ServerStream => parse_quote! {
+                tonic_mock::codegen::ServerStreamMethodMock<#request, #response>
+            },
+            MethodKind::BidirStream => parse_quote! {
+                tonic_mock::codegen::BidirStreamMethodMock<#request, #response>
+            },
+        }
+    }
+
+    fn trait_fn(&self, sig: &syn::Signature) -> TokenStream2 {
+        let field = self.field_name();
+        let not_implemented = format!("{} is not implemented", self.name);
+        let process = match self.kind {
+            MethodKind::Unary | MethodKind::ClientStream => {
+                quote! { deadline.response(mock.process_request(request)).await }
+            }
+            _ => quote! { deadline.stream(mock.process_request(request)) },
+        };
+        let name = self.name.to_string();
+        quote! {
+            #sig {
+                if let Some(mock) = &*self.#field {
+                    let deadline = tonic_mock::codegen::Deadline::new(
+                        request.metadata(),
+                        self.enforce_deadlines,
+                    );
+                    #process
+                } else {
+                    self.unmocked_calls.record(#name, &request);
+                    Err(tonic::Status::unimplemented(#not_implemented))
+                }
+            }
+        }
+    }
+}
+
+/// If the last segment of the type path is `ident` return the first generic type argument.
+fn generic_arg<'a>(ty: &'a Type, ident: &str) -> Option<&'a Type> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+    let segment = path.path.segments.last()?;
+    if segment.ident != ident {
+        return None;
+    }
+    let PathArguments::AngleBracketed(args) = &segment.arguments else {
+        return None;
+    };
+    args.args.iter().find_map(|x| match x {
+        GenericArgument::Type(ty) => Some(ty),
+        _ => None,
+    })
+}
+
+/// For a type like `Self::ListFeaturesStream` return `ListFeaturesStream`.
+fn associated_type(ty: &Type) -> Option<Ident> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+    let mut segments = path.path.segments.iter();
+    match (segments.next(), segments.next(), segments.next()) {
+        (Some(first), Some(second), None) if first.ident == "Self" => Some(second.ident.clone()),
+        _ => None,
+    }
+}
+
+/// Tonic declares the server streaming types as `type XStream: Stream<Item = Result<U, Status>>`
+/// this pulls out `U` so we can name the message type.
+fn stream_item(bounds: impl IntoIterator<Item = TypeParamBound>) -> Option<Type> {
+    bounds.into_iter().find_map(|bound| {
+        let TypeParamBound::Trait(bound) = bound else {
+            return None;
+        };
+        let segment = bound.path.segments.last()?;
+        if segment.ident != "Stream" {
+            return None;
+        }
+        let PathArguments::AngleBracketed(args) = &segment.arguments else {
+            return None;
+        };
+        args.args.iter().find_map(|x| match x {
+            GenericArgument::AssocType(item) if item.ident == "Item" => {
+                generic_arg(&item.ty, "Result").cloned()
+            }
+            _ => None,
+        })
+    })
+}
+
+fn generate_mock(trayt: &ItemTrait) -> TokenStream2 {
+    let streams = trayt
+        .items
+        .iter()
+        .filter_map(|x| match x {
+            TraitItem::Type(ty) => Some((ty.ident.clone(), stream_item(ty.bounds.clone())?)),
+            _ => None,
+        })
+        .collect::<BTreeMap<_, _>>();
+
+    let methods = trayt
+        .items
+        .iter()
+        .filter_map(|x| match x {
+            TraitItem::Fn(item) => MethodMockGen::from_trait_fn(item, &streams)
+                .map(|method| (method, item.sig.clone())),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    let trait_name = &trayt.ident;
+    let server_name = format_ident!("{}Server", trait_name);
+    let mock_name = format_ident!("Mock{}", trait_name);
+    let builder_name = format_ident!("Mock{}Builder", trait_name);
+
+    let mut builder_fields = vec![];
+    let mut builder_field_init = vec![];
+    let mut builder_methods = vec![];
+    let mut fields = vec![];
+    let mut field_init = vec![];
+    let mut verifies = vec![];
+    let mut resets = vec![];
+    let mut received = vec![];
+    let mut request_methods = vec![];
+    let mut trait_items = vec![];
+
+    for (method, sig) in &methods {
+        let field = method.field_name();
+        let field_type = method.field_type();
+        let mock_type = method.mock_type();
+        let mock_method = format_ident!("mock_{}", method.name);
+        let name = method.name.to_string();
+        builder_fields.push(quote! { #field: Option<#field_type> });
+        builder_field_init.push(quote! { #field: None });
+        let mocks_method = format_ident!("{}_mocks", method.name);
+        let mock_doc = method.mock_doc();
+        builder_methods.push(quote! {
+            #[doc = #mock_doc]
+            pub fn #mock_method(&mut self) -> &mut #mock_type {
+                self.#mocks_method().add_mock()
+            }
+
+            /// All the mocks for the method, i.e. to set the status sent when none match.
+            pub fn #mocks_method(&mut self) -> &mut #field_type {
+                self.#field.get_or_insert_with(|| tonic_mock::codegen::MethodMocks::new(#name))
+            }
+        });
+        fields.push(quote! {
+            #field: tonic_mock::codegen::Arc<Option<#field_type>>
+        });
+        field_init.push(quote! {
+            #field: tonic_mock::codegen::Arc::new(self.#field)
+        });
+        verifies.push(quote! {
+            if let Some(mock) = &*self.#field {
+                report.methods.extend(mock.verify());
+            }
+        });
+        resets.push(quote! {
+            if let Some(mock) = &*self.#field {
+                mock.reset();
+            }
+        });
+        let requests_method = format_ident!("{}_requests", method.name);
+        let request = &method.request;
+        request_methods.push(quote! {
+            /// The requests received for the method, in the order they were received.
+            pub async fn #requests_method(
+                &self,
+            ) -> Vec<tonic_mock::codegen::ReceivedRequest<#request>> {
+                match &*self.#field {
+                    Some(mock) => mock.received_requests(),
+                    None => vec![],
+                }
+            }
+        });
+        received.push(quote! {
+            for request in self.#requests_method().await {
+                calls.push(tonic_mock::codegen::ReceivedCall {
+                    method: #name.to_string(),
+                    request: request.map(|x| format!("{:?}", x)),
+                });
+            }
+        });
+        if let Some(stream_type) = &method.stream_type {
+            let response = &method.response;
+            trait_items.push(quote! {
+                type #stream_type = tonic_mock::ResponseStream<#response>;
+            });
+        }
+        trait_items.push(method.trait_fn(sig));
+    }
+
+    quote! {
+        /// Builder to set up the method mocks before creating the mock service.
+        pub struct #builder_name {
+            #(#builder_fields,)*
+            verify_on_drop: bool,
+            enforce_deadlines: bool,
+        }
 
-    if let Some((_, items)) = input.content.as_ref() {
-        if let Some(Item::Trait(trayt)) = items.iter().find(|x| matches!(x, Item::Trait(_))) {
-
-            let mut trait_methods = vec![];
-            let mut trait_types = BTreeMap::<String, String>::new();
-            for item in trayt.items.iter() {
-                match item {
-                    TraitItem::Fn(item) => {
-                        // Needs to be an async lock
-                        let mock_name = format!("{}_mock", item.sig.ident);
-                        let mock_method = format!("mock_{}", item.sig.ident);
-                        let builder_method = quote! {
-                            pub fn #mock_method(&mut self, method_mock: _ ) {
-                                self.#mock_name = Some(method_mock);
-                            }
-                        }.into();
-
-                        let trait_fn = quote! {
-                            #(item.sig) {
-                                if let Some(mock) = self.#mock_name.lock().await.as_mut() {
-                                    mock.process_request(request)
-                                } else {
-                                    Err(tonic::Status::unimplemented(format!("{} is not implemented", #(item.sig.ident))))
-                                }
-                            }
-                        }.into();
-
-                        trait_methods.push(MethodMockGen {
-                            builder_method, 
-                            trait_fn, 
-                            mock_type: (mock_name, format!("Option<()>"))
-                        });
-                    },
-                    TraitItem::Type(ty) => {
-
-                    },
-                    _ => {}
+        impl Default for #builder_name {
+            fn default() -> Self {
+                Self {
+                    #(#builder_field_init,)*
+                    verify_on_drop: true,
+                    enforce_deadlines: false,
                 }
             }
+        }
+
+        impl #builder_name {
+            #(#builder_methods)*
 
-            let trait_name = trayt.ident.to_string();
-            let mock_name = format!("Mock{}", trait_name);
+            /// Whether to verify the mock when the last handle to it is dropped, panicking if the
+            /// expectations weren't met. This is on by default.
+            pub fn verify_on_drop(&mut self, verify: bool) -> &mut Self {
+                self.verify_on_drop = verify;
+                self
+            }
 
+            /// Whether to fail calls with `DEADLINE_EXCEEDED` once the deadline the client set with
+            /// `grpc-timeout` has passed, like a real server would. This is off by default.
+            pub fn enforce_deadlines(&mut self, enforce: bool) -> &mut Self {
+                self.enforce_deadlines = enforce;
+                self
+            }
 
-            let expanded = quote! {
-                pub struct #mock_name {
-                    
+            pub fn build(self) -> #mock_name {
+                let mut mock = #mock_name {
+                    #(#field_init,)*
+                    server_handle: Default::default(),
+                    unmocked_calls: Default::default(),
+                    drop_verifier: None,
+                    enforce_deadlines: self.enforce_deadlines,
+                };
+                if self.verify_on_drop {
+                    let service = mock.service();
+                    mock.drop_verifier = Some(tonic_mock::codegen::Arc::new(
+                        tonic_mock::codegen::DropVerifier::new(move || service.report()),
+                    ));
                 }
+                mock
+            }
+        }
+
+        /// Mock implementation of the service, methods which haven't been mocked will return
+        /// `UNIMPLEMENTED`.
+        #[derive(Clone)]
+        pub struct #mock_name {
+            #(#fields,)*
+            server_handle: tonic_mock::codegen::Arc<
+                tonic_mock::codegen::RwLock<Option<tonic_mock::codegen::ServerHandle>>
+            >,
+            unmocked_calls: tonic_mock::codegen::UnmockedCalls,
+            // Shared by the clones the test holds but not the ones the servers hold, so the mock
+            // is verified when the test is done with it
+            drop_verifier: Option<tonic_mock::codegen::Arc<tonic_mock::codegen::DropVerifier>>,
+            enforce_deadlines: bool,
+        }
+
+        /// A mock with no methods mocked, the same as building one without configuring it so it's
+        /// still verified on drop.
+        impl Default for #mock_name {
+            fn default() -> Self {
+                Self::build().build()
+            }
+        }
 
-                impl #mock_name {
-                    pub fn new() -> Self {
-                        Self {
-                            // Default mocks (unavailable or not implemented whatever the default
-                            // is)
-                        }
-                    }
+        impl #mock_name {
+            pub fn build() -> #builder_name {
+                Default::default()
+            }
+
+            /// Start a server on localhost running this mock service.
+            pub async fn serve(&self) {
+                let handle = tonic_mock::codegen::serve(#server_name::new(self.service())).await;
+                *self.server_handle.write().await = Some(handle);
+            }
 
-                    // Functions to set the method mocks for each method
+            /// Register this mock service on a pooled mock server.
+            pub fn mount(&self, server: &tonic_mock::codegen::MockServer) {
+                server.register(#server_name::new(self.service()));
+            }
+
+            /// Check the expectations of all the method mocks, the report lists any which aren't
+            /// met.
+            pub async fn verify(&self) -> tonic_mock::codegen::VerificationReport {
+                self.report()
+            }
+
+            /// Stop the mock being verified when the last handle to it is dropped.
+            pub fn disable_verify_on_drop(&self) {
+                if let Some(verifier) = self.drop_verifier.as_ref() {
+                    verifier.set_enabled(false);
                 }
+            }
 
-                impl #trait_name for #mock_name {
-                    // type definitions - box stream time! 
-                    // we want to wrap the type in the traits with `core::pin::Pin<Box<dyn $OG_TYPE>>`
+            /// The verification report as things stand, calls still in flight are included.
+            fn report(&self) -> tonic_mock::codegen::VerificationReport {
+                let mut report = tonic_mock::codegen::VerificationReport::default();
+                #(#verifies)*
+                report.unmocked_calls = self.unmocked_calls.get();
+                report
+            }
 
-                    // method definitions. Graph the method mock from self and call it
+            /// A clone to hand to a server, this doesn't keep the mock from being verified on
+            /// drop. It doesn't share the server handle either, otherwise the server would keep
+            /// itself running after the test is done with the mock.
+            fn service(&self) -> Self {
+                Self {
+                    server_handle: Default::default(),
+                    drop_verifier: None,
+                    ..self.clone()
                 }
-            };
+            }
+
+            #(#request_methods)*
+
+            /// The requests received for all the mocked methods, in the order they were received.
+            /// Calls to methods which weren't mocked are in the verification report.
+            pub async fn received_requests(&self) -> Vec<tonic_mock::codegen::ReceivedCall> {
+                let mut calls = vec![];
+                #(#received)*
+                calls.sort_by_key(|x| x.request.received_at);
+                calls
+            }
+
+            /// Reset the state of the method mocks so they can be reused.
+            pub async fn reset(&self) {
+                #(#resets)*
+                self.unmocked_calls.clear();
+            }
+
+            pub async fn listening_address(&self) -> Option<String> {
+                let handle = self.server_handle.read().await;
+                handle.as_ref().map(|x| format!("http://{}", x.addr()))
+            }
+        }
+
+        #[tonic::async_trait]
+        impl #trait_name for #mock_name {
+            #(#trait_items)*
         }
     }
+}
+
+/// Attribute for the server module generated by tonic-build. For every service trait in the
+/// module this generates a `Mock{Service}` implementation and a `Mock{Service}Builder` to
+/// configure it, i.e. for the `RouteGuide` trait we get `MockRouteGuide` and
+/// `MockRouteGuideBuilder`.
+#[proc_macro_attribute]
+pub fn mock(_args: TokenStream, item: TokenStream) -> TokenStream {
+    let mut input = parse_macro_input!(item as ItemMod);
 
-    //input.content.as_mut().unwrap().1.push(syn::Item::Verbatim());
+    if let Some((_, items)) = input.content.as_mut() {
+        let mocks = items
+            .iter()
+            .filter_map(|x| match x {
+                Item::Trait(trayt) => Some(generate_mock(trayt)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
 
-    TokenStream::from(quote!{
+        for mock in mocks {
+            items.push(Item::Verbatim(mock));
+        }
+    }
+
+    TokenStream::from(quote! {
         #input
     })
 }
-
-
-